@@ -0,0 +1,1465 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, never, select, unbounded, Receiver, RecvTimeoutError};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::events::Stream;
+use crate::ignore::{self, Ignore};
+use crate::manifest;
+use crate::process::Deadline;
+use crate::ssh;
+use crate::sync::{
+    self, Delete, PullErr, PullOk, PullTarget, PushErr, PushOk, RsyncStats, Transfer,
+};
+use crate::time::format_duration;
+
+/// Version of the frames and messages, both sides must speak the same one.
+const PROTOCOL: u32 = 2;
+
+/// Files are compared and transferred in blocks of this size.
+const BLOCK_SIZE: u64 = 128 * 1024;
+
+/// How often the agent checks the timeout of the remote command.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How often the agent reports files changed by the remote command.
+const CHANGES_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Files of a remote directory to report changes of while the remote command runs.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Watched {
+    pub dir: String,
+    pub exclude: Vec<String>,
+    pub paths: Vec<String>,
+}
+
+/// Regular file or symlink relative to the synced directory.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    path: String,
+    size: u64,
+    mode: u32,
+    /// Target of a symlink.
+    link: Option<String>,
+    /// Hashes of the blocks of a regular file.
+    hashes: Vec<String>,
+}
+
+/// Blocks of a file to write, the frame data has them in order.
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileWrite {
+    path: String,
+    size: u64,
+    mode: u32,
+    link: Option<String>,
+    blocks: Vec<u64>,
+}
+
+impl Entry {
+    fn write(&self, blocks: Vec<u64>) -> FileWrite {
+        FileWrite {
+            path: self.path.clone(),
+            size: self.size,
+            mode: self.mode,
+            link: self.link.clone(),
+            blocks,
+        }
+    }
+}
+
+/// Frame header, followed by the data of `Write`, `Blocks` and `Output`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Message {
+    Hello {
+        protocol: u32,
+    },
+    /// Lists the directory, replied with `Files`.
+    Manifest {
+        dir: String,
+        exclude: Vec<String>,
+        paths: Vec<String>,
+    },
+    Files {
+        entries: Vec<Entry>,
+    },
+    /// Writes the blocks of a file, errors are reported at the next `Barrier`.
+    Write {
+        dir: String,
+        file: FileWrite,
+    },
+    /// Deletes files, errors are reported at the next `Barrier`.
+    Delete {
+        dir: String,
+        paths: Vec<String>,
+    },
+    /// Replied with `Done` once the writes and deletions before it are done.
+    Barrier,
+    Done {
+        errors: Vec<String>,
+    },
+    /// Reads blocks of a file, replied with `Blocks`.
+    Read {
+        dir: String,
+        entry: Entry,
+        blocks: Vec<u64>,
+    },
+    Blocks {
+        file: FileWrite,
+    },
+    /// Runs the script with `bash`, replied with `Output` chunks, `Changed` files if watched and then `Exit`.
+    #[serde(rename_all = "camelCase")]
+    Exec {
+        script: String,
+        timeout_ms: Option<u64>,
+        watch: Option<Watched>,
+    },
+    Output {
+        stream: Stream,
+    },
+    /// Files of the watched directory created, changed or deleted since the previous notification.
+    Changed {
+        paths: Vec<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Exit {
+        code: Option<i32>,
+        timed_out: bool,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Writes a frame: header length, JSON header, data length and data. Returns the size of the frame.
+fn write_frame(writer: &mut impl Write, message: &Message, data: &[u8]) -> io::Result<u64> {
+    let header = serde_json::to_vec(message)?;
+
+    writer.write_all(&(header.len() as u32).to_be_bytes())?;
+    writer.write_all(&header)?;
+    writer.write_all(&(data.len() as u64).to_be_bytes())?;
+    writer.write_all(data)?;
+    writer.flush()?;
+
+    Ok(12 + header.len() as u64 + data.len() as u64)
+}
+
+/// Reads a frame written by [`write_frame`], returns the message, its data and the size of the frame.
+fn read_frame(reader: &mut impl Read) -> io::Result<(Message, Vec<u8>, u64)> {
+    let mut length = [0; 4];
+    reader.read_exact(&mut length)?;
+    let mut header = vec![0; u32::from_be_bytes(length) as usize];
+    reader.read_exact(&mut header)?;
+
+    let mut length = [0; 8];
+    reader.read_exact(&mut length)?;
+    let mut data = vec![0; u64::from_be_bytes(length) as usize];
+    reader.read_exact(&mut data)?;
+
+    let message = serde_json::from_slice(&header)?;
+    let size = 12 + header.len() as u64 + data.len() as u64;
+
+    Ok((message, data, size))
+}
+
+/// Serves requests of the local machine on standard input and output until it disconnects.
+pub fn serve() -> Result<(), String> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut reader = BufReader::new(stdin.lock());
+    let mut writer = BufWriter::new(stdout.lock());
+
+    // Of writes and deletions since the previous barrier.
+    let mut errors = Vec::new();
+
+    loop {
+        let (message, data) = match read_frame(&mut reader) {
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(error) => return Err(format!("Could not read a request: {}", error)),
+            Ok((message, data, _)) => (message, data),
+        };
+
+        let (reply, data) = match message {
+            Message::Hello { .. } => (Some(Message::Hello { protocol: PROTOCOL }), Vec::new()),
+            Message::Manifest {
+                dir,
+                exclude,
+                paths,
+            } => (
+                Some(match manifest(&expand_home(&dir), &exclude, &paths, None) {
+                    Err(error) => Message::Error {
+                        message: format!("Could not list {}: {}", dir, error),
+                    },
+                    Ok(entries) => Message::Files { entries },
+                }),
+                Vec::new(),
+            ),
+            Message::Write { dir, file } => {
+                if let Err(error) = write_file(&expand_home(&dir), &file, &data) {
+                    errors.push(format!("Could not write {}: {}", file.path, error));
+                }
+                (None, Vec::new())
+            }
+            Message::Delete { dir, paths } => {
+                errors.extend(delete(&expand_home(&dir), &paths, None));
+                (None, Vec::new())
+            }
+            Message::Barrier => (
+                Some(Message::Done {
+                    errors: std::mem::take(&mut errors),
+                }),
+                Vec::new(),
+            ),
+            Message::Read { dir, entry, blocks } => {
+                match read_blocks(&expand_home(&dir), &entry, &blocks) {
+                    Err(error) => (
+                        Some(Message::Error {
+                            message: format!("Could not read {}: {}", entry.path, error),
+                        }),
+                        Vec::new(),
+                    ),
+                    Ok(data) => (
+                        Some(Message::Blocks {
+                            file: entry.write(blocks),
+                        }),
+                        data,
+                    ),
+                }
+            }
+            Message::Exec {
+                script,
+                timeout_ms,
+                watch,
+            } => {
+                exec(
+                    &mut writer,
+                    &script,
+                    timeout_ms.map(Duration::from_millis),
+                    watch,
+                )
+                .map_err(|error| format!("Could not send the output: {}", error))?;
+                (None, Vec::new())
+            }
+            _ => (
+                Some(Message::Error {
+                    message: String::from("Unexpected request"),
+                }),
+                Vec::new(),
+            ),
+        };
+
+        if let Some(reply) = reply {
+            write_frame(&mut writer, &reply, &data)
+                .map_err(|error| format!("Could not send a reply: {}", error))?;
+        }
+    }
+}
+
+/// Runs the script and sends its output, closing its standard input on timeout kills the script
+/// started with `kill_on_disconnect`.
+fn exec(
+    writer: &mut impl Write,
+    script: &str,
+    timeout: Option<Duration>,
+    watch: Option<Watched>,
+) -> io::Result<()> {
+    // Changes are best-effort, the script runs without them if the directory can't be watched.
+    // Watched before the script starts so none of its writes are missed.
+    let (mut changes, change_rx, _watcher) = match watch.and_then(Changes::watch) {
+        None => (None, never(), None),
+        Some((changes, change_rx, watcher)) => (Some(changes), change_rx, Some(watcher)),
+    };
+
+    let mut child = match Command::new("bash")
+        .arg("-c")
+        .arg(script)
+        .stdin(match timeout {
+            None => Stdio::null(),
+            Some(_) => Stdio::piped(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Err(error) => {
+            let message = format!("Could not execute bash: {}\n", error);
+            write_frame(
+                writer,
+                &Message::Output {
+                    stream: Stream::Stderr,
+                },
+                message.as_bytes(),
+            )?;
+            write_frame(
+                writer,
+                &Message::Exit {
+                    code: None,
+                    timed_out: false,
+                },
+                &[],
+            )?;
+            return Ok(());
+        }
+        Ok(child) => child,
+    };
+
+    let (output_tx, output_rx) = unbounded();
+
+    let outputs: [(Stream, Box<dyn Read + Send>); 2] = [
+        (Stream::Stdout, Box::new(child.stdout.take().unwrap())),
+        (Stream::Stderr, Box::new(child.stderr.take().unwrap())),
+    ];
+
+    for (stream, mut output) in outputs {
+        let output_tx = output_tx.clone();
+
+        thread::spawn(move || {
+            let mut buffer = [0; 8192];
+
+            while let Ok(read) = output.read(&mut buffer) {
+                if read == 0 || output_tx.send((stream, buffer[..read].to_vec())).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    drop(output_tx);
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut stdin = child.stdin.take();
+    let mut timed_out = false;
+    let mut reported = Instant::now();
+
+    loop {
+        select! {
+            recv(output_rx) -> output => match output {
+                Ok((stream, chunk)) => {
+                    write_frame(writer, &Message::Output { stream }, &chunk)?;
+                }
+                Err(_) => break,
+            },
+            recv(change_rx) -> event => {
+                if let (Some(changes), Ok(Ok(event))) = (&mut changes, event) {
+                    changes.collect(&event);
+                }
+            },
+            default(POLL_INTERVAL) => {}
+        }
+
+        if let Some(changes) = &mut changes {
+            if reported.elapsed() >= CHANGES_INTERVAL {
+                changes.report(writer)?;
+                reported = Instant::now();
+            }
+        }
+
+        if stdin.is_some() && matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+            stdin = None;
+            timed_out = true;
+        }
+    }
+
+    drop(stdin);
+    let status = child.wait()?;
+
+    if let Some(changes) = &mut changes {
+        // Events of the last writes might still be on their way.
+        thread::sleep(POLL_INTERVAL);
+
+        for event in change_rx.try_iter().flatten() {
+            changes.collect(&event);
+        }
+
+        changes.report(writer)?;
+    }
+
+    write_frame(
+        writer,
+        &Message::Exit {
+            code: if timed_out { None } else { status.code() },
+            timed_out,
+        },
+        &[],
+    )?;
+
+    Ok(())
+}
+
+/// Changed files of the watched directory not reported yet.
+struct Changes {
+    dir: PathBuf,
+    exclude: Vec<String>,
+    paths: Vec<String>,
+    pending: BTreeSet<String>,
+}
+
+impl Changes {
+    fn watch(
+        watched: Watched,
+    ) -> Option<(Self, Receiver<notify::Result<Event>>, RecommendedWatcher)> {
+        let dir = expand_home(&watched.dir);
+        let (change_tx, change_rx) = unbounded();
+
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = change_tx.send(event);
+        })
+        .ok()?;
+        watcher.watch(&dir, RecursiveMode::Recursive).ok()?;
+
+        let changes = Self {
+            dir,
+            exclude: watched.exclude,
+            paths: watched.paths,
+            pending: BTreeSet::new(),
+        };
+
+        Some((changes, change_rx, watcher))
+    }
+
+    fn collect(&mut self, event: &Event) {
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+
+        for path in &event.paths {
+            let relative = match path.strip_prefix(&self.dir) {
+                Ok(relative) => relative.to_string_lossy().to_string(),
+                Err(_) => continue,
+            };
+
+            // Directories show up along with their files.
+            if relative.is_empty()
+                || path.is_dir()
+                || sync::is_local_state(&relative)
+                || ignore::excluded(&self.exclude, &relative, false)
+                || !manifest::within(&self.paths, &relative, false)
+            {
+                continue;
+            }
+
+            self.pending.insert(relative);
+        }
+    }
+
+    fn report(&mut self, writer: &mut impl Write) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let paths = std::mem::take(&mut self.pending).into_iter().collect();
+        write_frame(writer, &Message::Changed { paths }, &[])?;
+        writer.flush()
+    }
+}
+
+/// Agent on the remote machine started over `ssh`, closed once dropped.
+pub struct Session {
+    /// Shared with the watchdog of [`Session::limited`].
+    child: Arc<Mutex<Child>>,
+    writer: BufWriter<ChildStdin>,
+    reader: BufReader<ChildStdout>,
+    sent: u64,
+    received: u64,
+}
+
+impl Session {
+    /// Starts `remote.agent` on the remote machine and checks it speaks the same protocol.
+    pub fn connect(config: &Config) -> Result<Self, String> {
+        let agent = config.remote.agent.as_deref().unwrap_or("mainframer");
+        let destination = ssh::destination(&config.remote.host, config.remote.user.as_ref());
+        let mut command = ssh::command(&config.remote, &destination);
+
+        command
+            .arg(&destination)
+            .arg(format!("{} agent", agent))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+
+        tracing::debug!("Starting agent: {:?}", command);
+
+        let mut child = command
+            .spawn()
+            .map_err(|error| format!("Could not start the agent: {}", error))?;
+
+        let mut session = Self {
+            writer: BufWriter::new(child.stdin.take().unwrap()),
+            reader: BufReader::new(child.stdout.take().unwrap()),
+            child: Arc::new(Mutex::new(child)),
+            sent: 0,
+            received: 0,
+        };
+
+        session.send(&Message::Hello { protocol: PROTOCOL }, &[])?;
+
+        match session.receive()? {
+            (Message::Hello { protocol }, _) if protocol == PROTOCOL => Ok(session),
+            (Message::Hello { protocol }, _) => Err(format!(
+                "Agent on the remote machine speaks protocol {}, expected {}, update Mainframer there.",
+                protocol, PROTOCOL
+            )),
+            _ => Err(String::from("Agent on the remote machine didn't say hello.")),
+        }
+    }
+
+    /// Pushes files changed according to the manifests and deletes the ones missing locally.
+    pub fn push(
+        &mut self,
+        local_dir_absolute_path: &Path,
+        config: &Config,
+        ignore: &Option<Ignore>,
+        deadline: Deadline,
+    ) -> Result<PushOk, PushErr> {
+        let start_time = Instant::now();
+        let (sent, received) = (self.sent, self.received);
+        let timeout = deadline.timeout(config.push.timeout.map(Duration::from_secs));
+
+        match self.limited(timeout, |session| {
+            session.push_files(local_dir_absolute_path, config, ignore)
+        }) {
+            Err((message, timed_out)) => Err(PushErr {
+                duration: start_time.elapsed(),
+                message,
+                timed_out,
+            }),
+            Ok(mut stats) => {
+                stats.bytes_sent = self.sent - sent;
+                stats.bytes_received = self.received - received;
+
+                Ok(PushOk {
+                    duration: start_time.elapsed(),
+                    stats,
+                })
+            }
+        }
+    }
+
+    fn push_files(
+        &mut self,
+        local_dir_absolute_path: &Path,
+        config: &Config,
+        ignore: &Option<Ignore>,
+    ) -> Result<RsyncStats, String> {
+        let exclude = ignore.as_ref().map(Ignore::push).unwrap_or_default();
+        let remote_dir = sync::project_dir_on_remote_machine(config, local_dir_absolute_path);
+
+        let mut blocks = manifest::Blocks::load(local_dir_absolute_path);
+        let source = manifest(local_dir_absolute_path, &exclude, &[], Some(&mut blocks))
+            .map_err(|error| format!("Could not list local files: {}", error))?;
+        blocks.save(local_dir_absolute_path);
+        let destination = self.manifest(&remote_dir, &exclude, &[])?;
+        let plan = plan(&source, &destination);
+
+        for (entry, blocks) in &plan.writes {
+            let data = read_blocks(local_dir_absolute_path, entry, blocks)
+                .map_err(|error| format!("Could not read {}: {}", entry.path, error))?;
+
+            self.send(
+                &Message::Write {
+                    dir: remote_dir.clone(),
+                    file: entry.write(blocks.clone()),
+                },
+                &data,
+            )?;
+        }
+
+        let deletions = deletable(&plan.deletions, config.push.delete, &config.push.protect);
+
+        if !deletions.is_empty() {
+            self.send(
+                &Message::Delete {
+                    dir: remote_dir,
+                    paths: deletions,
+                },
+                &[],
+            )?;
+        }
+
+        self.send(&Message::Barrier, &[])?;
+
+        match self.receive()? {
+            (Message::Done { errors }, _) if errors.is_empty() => Ok(stats(&source, &plan)),
+            (Message::Done { errors }, _) => Err(errors.join(", ")),
+            (message, _) => Err(unexpected(message)),
+        }
+    }
+
+    /// Pulls files changed according to the manifests, deletes local ones missing on the remote
+    /// machine if the target allows that.
+    pub fn pull(
+        &mut self,
+        local_dir_absolute_path: &Path,
+        config: &Config,
+        ignore: &Option<Ignore>,
+        target: &PullTarget,
+        deadline: Deadline,
+    ) -> Result<PullOk, PullErr> {
+        let start_time = Instant::now();
+        let (sent, received) = (self.sent, self.received);
+        let timeout = deadline.timeout(config.pull.timeout.map(Duration::from_secs));
+
+        match self.limited(timeout, |session| {
+            session.pull_files(local_dir_absolute_path, config, ignore, target)
+        }) {
+            Err((message, timed_out)) => Err(PullErr {
+                duration: start_time.elapsed(),
+                message,
+                timed_out,
+            }),
+            Ok(mut stats) => {
+                stats.bytes_sent = self.sent - sent;
+                stats.bytes_received = self.received - received;
+
+                Ok(PullOk {
+                    duration: start_time.elapsed(),
+                    transfers: vec![Transfer {
+                        duration: start_time.elapsed(),
+                        stats,
+                    }],
+                })
+            }
+        }
+    }
+
+    fn pull_files(
+        &mut self,
+        local_dir_absolute_path: &Path,
+        config: &Config,
+        ignore: &Option<Ignore>,
+        target: &PullTarget,
+    ) -> Result<RsyncStats, String> {
+        let exclude = ignore.as_ref().map(Ignore::pull).unwrap_or_default();
+        let paths = &config.pull.paths;
+        let remote_dir = sync::project_dir_on_remote_machine(config, local_dir_absolute_path);
+
+        let source = self.manifest(&remote_dir, &exclude, paths)?;
+        // Only the project has a cache, not other targets.
+        let mut blocks = if target.dir == local_dir_absolute_path {
+            Some(manifest::Blocks::load(local_dir_absolute_path))
+        } else {
+            None
+        };
+        let destination = manifest(&target.dir, &exclude, paths, blocks.as_mut())
+            .map_err(|error| format!("Could not list local files: {}", error))?;
+        let backup_dir = if config.pull.backup {
+            Some(sync::trash_dir(local_dir_absolute_path))
+        } else {
+            None
+        };
+
+        if let Some(blocks) = blocks {
+            blocks.save(local_dir_absolute_path);
+        }
+        let plan = plan(&source, &destination);
+
+        let mut errors = Vec::new();
+        let writer = &mut self.writer;
+        let reader = &mut self.reader;
+
+        // Requests go from another thread, replies would block the agent otherwise.
+        let sent = thread::scope(|scope| {
+            let requests = scope.spawn(|| -> io::Result<u64> {
+                let mut sent = 0;
+
+                for (entry, blocks) in &plan.writes {
+                    sent += write_frame(
+                        writer,
+                        &Message::Read {
+                            dir: remote_dir.clone(),
+                            entry: (*entry).clone(),
+                            blocks: blocks.clone(),
+                        },
+                        &[],
+                    )?;
+                }
+
+                Ok(sent)
+            });
+
+            for _ in &plan.writes {
+                let (message, data, size) = match read_frame(reader) {
+                    Err(error) => {
+                        errors.push(format!("Could not receive a file: {}", error));
+                        break;
+                    }
+                    Ok(frame) => frame,
+                };
+
+                self.received += size;
+
+                match message {
+                    Message::Blocks { file } => {
+                        let backed_up = match &backup_dir {
+                            Some(backup_dir) => back_up(&target.dir, &file.path, backup_dir, true),
+                            None => Ok(()),
+                        };
+
+                        if let Err(error) =
+                            backed_up.and_then(|_| write_file(&target.dir, &file, &data))
+                        {
+                            errors.push(format!("Could not write {}: {}", file.path, error));
+                        }
+                    }
+                    Message::Error { message } => errors.push(message),
+                    message => errors.push(unexpected(message)),
+                }
+            }
+
+            requests.join().expect("Could not send read requests")
+        });
+
+        match sent {
+            Err(error) => errors.push(format!("Could not request a file: {}", error)),
+            Ok(sent) => self.sent += sent,
+        }
+
+        if target.delete {
+            let deletions = deletable(&plan.deletions, config.pull.delete, &config.pull.protect);
+            errors.extend(delete(&target.dir, &deletions, backup_dir.as_deref()));
+        }
+
+        if errors.is_empty() {
+            Ok(stats(&source, &plan))
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+
+    /// Runs the transfer, kills the connection once it takes longer than the timeout
+    /// since requests to the agent can't be interrupted otherwise. Errors tell whether it has timed out.
+    fn limited<T>(
+        &mut self,
+        timeout: Option<Duration>,
+        transfer: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, (String, bool)> {
+        let timeout = match timeout {
+            None => return transfer(self).map_err(|message| (message, false)),
+            Some(timeout) => timeout,
+        };
+
+        let child = Arc::clone(&self.child);
+        let (done_tx, done_rx) = bounded::<()>(0);
+
+        let (result, timed_out) = thread::scope(|scope| {
+            let watchdog = scope.spawn(move || {
+                let timed_out = done_rx.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout);
+
+                if timed_out {
+                    let _ = child.lock().unwrap().kill();
+                }
+
+                timed_out
+            });
+
+            let result = transfer(self);
+            drop(done_tx);
+
+            (
+                result,
+                watchdog.join().expect("Could not watch the transfer"),
+            )
+        });
+
+        match result {
+            _ if timed_out => Err((
+                format!("Agent timed out after {}", format_duration(timeout)),
+                true,
+            )),
+            result => result.map_err(|message| (message, false)),
+        }
+    }
+
+    /// Runs the script with `bash` in the home directory, passes output chunks to `output`
+    /// and files of `watch` changed meanwhile to `changed`.
+    ///
+    /// Returns the exit code, `None` without one, and whether the script has timed out.
+    pub fn exec(
+        &mut self,
+        script: &str,
+        timeout: Option<Duration>,
+        watch: Option<Watched>,
+        mut output: impl FnMut(Stream, &[u8]),
+        mut changed: impl FnMut(Vec<String>),
+    ) -> Result<(Option<i32>, bool), String> {
+        self.send(
+            &Message::Exec {
+                script: script.to_string(),
+                timeout_ms: timeout.map(|timeout| timeout.as_millis() as u64),
+                watch,
+            },
+            &[],
+        )?;
+
+        loop {
+            match self.receive()? {
+                (Message::Output { stream }, data) => output(stream, &data),
+                (Message::Changed { paths }, _) => changed(paths),
+                (Message::Exit { code, timed_out }, _) => return Ok((code, timed_out)),
+                (message, _) => return Err(unexpected(message)),
+            }
+        }
+    }
+
+    fn manifest(
+        &mut self,
+        dir: &str,
+        exclude: &[String],
+        paths: &[String],
+    ) -> Result<Vec<Entry>, String> {
+        self.send(
+            &Message::Manifest {
+                dir: dir.to_string(),
+                exclude: exclude.to_vec(),
+                paths: paths.to_vec(),
+            },
+            &[],
+        )?;
+
+        match self.receive()? {
+            (Message::Files { entries }, _) => Ok(entries),
+            (Message::Error { message }, _) => Err(message),
+            (message, _) => Err(unexpected(message)),
+        }
+    }
+
+    fn send(&mut self, message: &Message, data: &[u8]) -> Result<(), String> {
+        self.sent += write_frame(&mut self.writer, message, data)
+            .map_err(|error| format!("Could not send to the agent: {}", error))?;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<(Message, Vec<u8>), String> {
+        let (message, data, size) = read_frame(&mut self.reader)
+            .map_err(|error| format!("Could not receive from the agent: {}", error))?;
+        self.received += size;
+        Ok((message, data))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // The agent exits once its standard input is closed along with the connection.
+        let mut child = self.child.lock().unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+fn unexpected(message: Message) -> String {
+    match message {
+        Message::Error { message } => message,
+        message => format!("Unexpected reply of the agent: {:?}", message),
+    }
+}
+
+/// Changes that make the destination the same as the source.
+#[derive(Debug, Eq, PartialEq)]
+struct Plan<'a> {
+    /// Changed files with indices of the blocks that differ.
+    writes: Vec<(&'a Entry, Vec<u64>)>,
+    deletions: Vec<String>,
+}
+
+fn plan<'a>(source: &'a [Entry], destination: &[Entry]) -> Plan<'a> {
+    let existing: BTreeMap<&str, &Entry> = destination
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+
+    let writes = source
+        .iter()
+        .filter_map(|entry| {
+            let all = (0..entry.hashes.len() as u64).collect();
+
+            let blocks: Vec<u64> = match existing.get(entry.path.as_str()) {
+                None => all,
+                Some(existing) if existing.link.is_some() || entry.link.is_some() => {
+                    if existing.link == entry.link {
+                        Vec::new()
+                    } else {
+                        all
+                    }
+                }
+                Some(existing) => entry
+                    .hashes
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, hash)| existing.hashes.get(*index) != Some(hash))
+                    .map(|(index, _)| index as u64)
+                    .collect(),
+            };
+
+            let unchanged = matches!(existing.get(entry.path.as_str()), Some(existing)
+                if blocks.is_empty() && existing.size == entry.size && existing.mode == entry.mode);
+
+            if unchanged {
+                None
+            } else {
+                Some((entry, blocks))
+            }
+        })
+        .collect();
+
+    let paths: BTreeMap<&str, ()> = source
+        .iter()
+        .map(|entry| (entry.path.as_str(), ()))
+        .collect();
+
+    let deletions = destination
+        .iter()
+        .filter(|entry| !paths.contains_key(entry.path.as_str()))
+        .map(|entry| entry.path.clone())
+        .collect();
+
+    Plan { writes, deletions }
+}
+
+/// Deletions of the plan allowed by the `delete` and `protect` settings, the agent always deletes after writing.
+fn deletable(deletions: &[String], delete: Delete, protect: &[String]) -> Vec<String> {
+    if delete == Delete::Never {
+        return Vec::new();
+    }
+
+    deletions
+        .iter()
+        .filter(|path| !ignore::excluded(protect, path, false))
+        .cloned()
+        .collect()
+}
+
+fn stats(source: &[Entry], plan: &Plan) -> RsyncStats {
+    RsyncStats {
+        files: source.len() as u64,
+        files_transferred: plan.writes.len() as u64,
+        total_size: source.iter().map(|entry| entry.size).sum(),
+        ..RsyncStats::default()
+    }
+}
+
+/// Files of the directory not excluded and within `paths` if set, empty if the directory is missing.
+///
+/// Block hashes come from `blocks` if given, the cache of the local project.
+fn manifest(
+    dir: &Path,
+    exclude: &[String],
+    paths: &[String],
+    mut blocks: Option<&mut manifest::Blocks>,
+) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    let result = manifest::walk(dir, exclude, paths, &mut |path, full_path, metadata| {
+        let mode = metadata.permissions().mode() & 0o7777;
+
+        if metadata.file_type().is_symlink() {
+            let link = fs::read_link(full_path)?.to_string_lossy().to_string();
+            entries.push(Entry {
+                path,
+                size: 0,
+                mode,
+                link: Some(link),
+                hashes: Vec::new(),
+            });
+            return Ok(());
+        }
+
+        let hashes = match &mut blocks {
+            Some(blocks) => blocks.hashes(path.clone(), metadata, || hash_blocks(full_path)),
+            None => hash_blocks(full_path),
+        };
+
+        let hashes = match hashes {
+            // Files might vanish while a build is running.
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        };
+        entries.push(Entry {
+            path,
+            size: metadata.len(),
+            mode,
+            link: None,
+            hashes,
+        });
+        Ok(())
+    });
+
+    match result {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        result => result?,
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+fn hash_blocks(path: &Path) -> io::Result<Vec<String>> {
+    let mut file = fs::File::open(path)?;
+    let mut block = vec![0; BLOCK_SIZE as usize];
+    let mut hashes = Vec::new();
+
+    loop {
+        let read = read_block(&mut file, &mut block)?;
+
+        if read == 0 {
+            return Ok(hashes);
+        }
+
+        let digest = Sha256::digest(&block[..read]);
+        hashes.push(
+            digest[..16]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        );
+    }
+}
+
+/// Fills the block unless the file ends, returns the number of read bytes.
+fn read_block(file: &mut fs::File, block: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+
+    while read < block.len() {
+        match file.read(&mut block[read..])? {
+            0 => break,
+            count => read += count,
+        }
+    }
+
+    Ok(read)
+}
+
+fn read_blocks(dir: &Path, entry: &Entry, blocks: &[u64]) -> io::Result<Vec<u8>> {
+    if entry.link.is_some() {
+        return Ok(Vec::new());
+    }
+
+    let mut file = fs::File::open(dir.join(&entry.path))?;
+    let mut data = Vec::new();
+
+    for index in blocks {
+        let (start, length) = block_range(entry.size, *index);
+        let mut block = vec![0; length];
+
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block)?;
+        data.extend_from_slice(&block);
+    }
+
+    Ok(data)
+}
+
+fn write_file(dir: &Path, file: &FileWrite, data: &[u8]) -> io::Result<()> {
+    let path = within(dir, &file.path)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let existing = fs::symlink_metadata(&path).ok();
+
+    if file.link.is_some() || matches!(existing, Some(ref metadata) if !metadata.is_file()) {
+        match existing {
+            Some(ref metadata) if metadata.is_dir() => fs::remove_dir_all(&path)?,
+            Some(_) => fs::remove_file(&path)?,
+            None => {}
+        }
+    }
+
+    if let Some(link) = &file.link {
+        return symlink(link, &path);
+    }
+
+    // Blocks that haven't changed stay in place.
+    let mut output = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    output.set_len(file.size)?;
+
+    let mut offset = 0;
+
+    for index in &file.blocks {
+        let (start, length) = block_range(file.size, *index);
+
+        let block = data.get(offset..offset + length).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Blocks are shorter than the file",
+            )
+        })?;
+
+        output.seek(SeekFrom::Start(start))?;
+        output.write_all(block)?;
+        offset += length;
+    }
+
+    fs::set_permissions(&path, Permissions::from_mode(file.mode))
+}
+
+/// Offset and length of the block, the last one might be shorter.
+fn block_range(size: u64, index: u64) -> (u64, usize) {
+    let start = index * BLOCK_SIZE;
+    (start, size.saturating_sub(start).min(BLOCK_SIZE) as usize)
+}
+
+/// Deletes the files or moves them into `backup_dir` if set, returns the errors.
+fn delete(dir: &Path, paths: &[String], backup_dir: Option<&Path>) -> Vec<String> {
+    paths
+        .iter()
+        .filter_map(|path| {
+            let result = match backup_dir {
+                Some(backup_dir) => back_up(dir, path, backup_dir, false),
+                None => within(dir, path).and_then(fs::remove_file),
+            };
+
+            match result {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                    Some(format!("Could not delete {}: {}", path, error))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Moves the file to the same path within `backup_dir` like `rsync --backup-dir`, copies it instead
+/// if it is about to be overwritten since unchanged blocks stay in place.
+fn back_up(dir: &Path, path: &str, backup_dir: &Path, copy: bool) -> io::Result<()> {
+    let source = within(dir, path)?;
+
+    match fs::symlink_metadata(&source) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Ok(metadata) if metadata.is_dir() => return Ok(()),
+        result => result?,
+    };
+
+    let backup = backup_dir.join(path);
+
+    if let Some(parent) = backup.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Of an earlier pull of the run.
+    let _ = fs::remove_file(&backup);
+
+    match fs::read_link(&source) {
+        _ if !copy => fs::rename(&source, &backup),
+        Ok(link) => symlink(link, &backup),
+        Err(_) => fs::copy(&source, &backup).map(|_| ()),
+    }
+}
+
+/// Joins the relative path of the other side to the directory, refusing paths that would leave it:
+/// absolute ones, ones with `..` and ones going through symlinks.
+fn within(dir: &Path, path: &str) -> io::Result<PathBuf> {
+    let relative = Path::new(path);
+    let mut joined = dir.to_path_buf();
+
+    for component in relative.components() {
+        let is_symlink = matches!(fs::symlink_metadata(&joined), Ok(metadata) if metadata.file_type().is_symlink());
+
+        if !matches!(component, Component::Normal(_)) || (joined != dir && is_symlink) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Path is outside of the project",
+            ));
+        }
+
+        joined.push(component);
+    }
+
+    if joined == dir {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Path is empty"));
+    }
+
+    Ok(joined)
+}
+
+/// Resolves `~` the way the remote shell would, `ssh` starts the agent in the home directory.
+fn expand_home(dir: &str) -> PathBuf {
+    match (dir.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(relative), Some(home)) => Path::new(&home).join(relative),
+        _ if dir == "~" => env::var_os("HOME").map(PathBuf::from).unwrap_or_default(),
+        _ => PathBuf::from(dir),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, size: u64, hashes: &[&str]) -> Entry {
+        Entry {
+            path: String::from(path),
+            size,
+            mode: 0o644,
+            link: None,
+            hashes: hashes.iter().map(|hash| hash.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let mut buffer = Vec::new();
+        let message = Message::Output {
+            stream: Stream::Stderr,
+        };
+
+        let written = write_frame(&mut buffer, &message, b"error\n").unwrap();
+        let (read, data, size) = read_frame(&mut buffer.as_slice()).unwrap();
+
+        assert_eq!(read, message);
+        assert_eq!(data, b"error\n");
+        assert_eq!(size, written);
+    }
+
+    #[test]
+    fn plan_changed_blocks_and_deletions() {
+        let source = vec![
+            entry("new.rs", 10, &["a"]),
+            entry("same.rs", 10, &["b"]),
+            entry("changed.rs", 300_000, &["c", "d", "e"]),
+        ];
+        let destination = vec![
+            entry("same.rs", 10, &["b"]),
+            entry("changed.rs", 200_000, &["c", "x"]),
+            entry("deleted.rs", 10, &["f"]),
+        ];
+
+        assert_eq!(
+            plan(&source, &destination),
+            Plan {
+                writes: vec![(&source[0], vec![0]), (&source[2], vec![1, 2])],
+                deletions: vec![String::from("deleted.rs")],
+            }
+        );
+    }
+
+    #[test]
+    fn deletable_protected_and_kept() {
+        let deletions = vec![
+            String::from("local.properties"),
+            String::from(".idea/workspace.xml"),
+            String::from("src/deleted.rs"),
+        ];
+        let protect = vec![String::from("/local.properties"), String::from(".idea/")];
+
+        assert_eq!(
+            deletable(&deletions, Delete::After, &protect),
+            vec![String::from("src/deleted.rs")]
+        );
+        assert!(deletable(&deletions, Delete::Never, &[]).is_empty());
+    }
+
+    #[test]
+    fn within_rejects_paths_outside() {
+        let dir = Path::new("/project");
+
+        assert_eq!(
+            within(dir, "src/main.rs").unwrap(),
+            PathBuf::from("/project/src/main.rs")
+        );
+        assert!(within(dir, "/etc/passwd").is_err());
+        assert!(within(dir, "../.bashrc").is_err());
+        assert!(within(dir, "src/../../.bashrc").is_err());
+        assert!(within(dir, "").is_err());
+    }
+
+    #[test]
+    fn within_rejects_paths_through_symlinks() {
+        let dir = env::temp_dir().join(format!("mainframer-within-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        symlink(env::temp_dir(), dir.join("link")).unwrap();
+
+        let through = within(&dir, "link/file");
+        let link = within(&dir, "link");
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(through.is_err());
+        assert!(link.is_ok());
+    }
+
+    #[test]
+    fn changes_collect_skips_excluded_and_local_state() {
+        let mut changes = Changes {
+            dir: PathBuf::from("/project"),
+            exclude: vec![String::from("build/tmp/")],
+            paths: Vec::new(),
+            pending: BTreeSet::new(),
+        };
+
+        changes.collect(
+            &Event::new(EventKind::Any)
+                .add_path(PathBuf::from("/project/build/out.txt"))
+                .add_path(PathBuf::from("/project/build/tmp/scratch"))
+                .add_path(PathBuf::from("/project/.mainframer/cache/manifest"))
+                .add_path(PathBuf::from("/elsewhere/file")),
+        );
+
+        assert_eq!(
+            changes.pending.into_iter().collect::<Vec<_>>(),
+            vec![String::from("build/out.txt")]
+        );
+    }
+
+    #[test]
+    fn exec_reports_changed_files() {
+        let dir = env::temp_dir().join(format!("mainframer-changes-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut buffer = Vec::new();
+        let watched = Watched {
+            dir: dir.to_string_lossy().to_string(),
+            exclude: Vec::new(),
+            paths: Vec::new(),
+        };
+        let script = format!("echo done > {}/out.txt", dir.to_string_lossy());
+        exec(&mut buffer, &script, None, Some(watched)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut reader = buffer.as_slice();
+        let mut changed = Vec::new();
+
+        loop {
+            match read_frame(&mut reader).unwrap().0 {
+                Message::Changed { paths } => changed.extend(paths),
+                Message::Exit { code, .. } => {
+                    assert_eq!(code, Some(0));
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        assert_eq!(changed, vec![String::from("out.txt")]);
+    }
+
+    fn session(program: &str) -> Session {
+        let mut child = Command::new(program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        Session {
+            writer: BufWriter::new(child.stdin.take().unwrap()),
+            reader: BufReader::new(child.stdout.take().unwrap()),
+            child: Arc::new(Mutex::new(child)),
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    #[test]
+    fn limited_kills_the_connection_on_timeout() {
+        let mut session = session("cat");
+
+        // Nothing ever comes back from `cat` without a request.
+        let result = session.limited(Some(Duration::from_millis(100)), Session::receive);
+
+        assert!(matches!(result, Err((_, true))));
+    }
+
+    #[test]
+    fn limited_in_time() {
+        let mut session = session("cat");
+
+        assert_eq!(
+            session.limited(Some(Duration::from_secs(10)), |_| Ok(1)),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn delete_and_overwrite_into_backup_dir() {
+        let root = env::temp_dir().join(format!("mainframer-backup-test-{}", std::process::id()));
+        let (dir, backup_dir) = (root.join("project"), root.join("trash"));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::write(dir.join("build/old.txt"), "old").unwrap();
+        fs::write(dir.join("local.properties"), "sdk").unwrap();
+
+        assert!(delete(&dir, &[String::from("build/old.txt")], Some(&backup_dir)).is_empty());
+        back_up(&dir, "local.properties", &backup_dir, true).unwrap();
+        back_up(&dir, "missing.txt", &backup_dir, true).unwrap();
+
+        let deleted = dir.join("build/old.txt").exists();
+        let overwritten = fs::read_to_string(dir.join("local.properties")).unwrap();
+        let backups = [
+            fs::read_to_string(backup_dir.join("build/old.txt")).unwrap(),
+            fs::read_to_string(backup_dir.join("local.properties")).unwrap(),
+        ];
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(!deleted);
+        assert_eq!(overwritten, "sdk");
+        assert_eq!(backups, [String::from("old"), String::from("sdk")]);
+    }
+
+    #[test]
+    fn plan_mode_change_without_blocks() {
+        let source = vec![Entry {
+            mode: 0o755,
+            ..entry("run.sh", 10, &["a"])
+        }];
+        let destination = vec![entry("run.sh", 10, &["a"])];
+
+        assert_eq!(
+            plan(&source, &destination).writes,
+            vec![(&source[0], vec![])]
+        );
+    }
+
+    #[test]
+    fn sync_between_directories() {
+        let root = env::temp_dir().join(format!("mainframer-agent-test-{}", std::process::id()));
+        let (source_dir, destination_dir) = (root.join("source"), root.join("destination"));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(source_dir.join("src")).unwrap();
+        fs::create_dir_all(destination_dir.join("build")).unwrap();
+        fs::write(source_dir.join("src/main.rs"), vec![7; 200_000]).unwrap();
+        fs::write(destination_dir.join("stale.rs"), "stale").unwrap();
+        fs::write(destination_dir.join("build/out"), "ignored").unwrap();
+
+        let exclude = vec![String::from("build")];
+        let source = manifest(&source_dir, &exclude, &[], None).unwrap();
+        let destination = manifest(&destination_dir, &exclude, &[], None).unwrap();
+        let plan = plan(&source, &destination);
+
+        for (entry, blocks) in &plan.writes {
+            let data = read_blocks(&source_dir, entry, blocks).unwrap();
+            write_file(&destination_dir, &entry.write(blocks.clone()), &data).unwrap();
+        }
+        assert!(delete(&destination_dir, &plan.deletions, None).is_empty());
+
+        assert_eq!(
+            fs::read(destination_dir.join("src/main.rs")).unwrap(),
+            vec![7; 200_000]
+        );
+        assert!(!destination_dir.join("stale.rs").exists());
+        assert!(destination_dir.join("build/out").exists());
+        assert_eq!(
+            manifest(&destination_dir, &exclude, &[], None).unwrap(),
+            source
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
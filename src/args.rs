@@ -1,18 +1,263 @@
-use clap::{ArgAction, Parser};
+use std::path::PathBuf;
+
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+
+use crate::trace::TraceFormat;
 
 #[derive(Parser)] // requires `derive` feature
-#[clap(author, version, about, long_about = None)]
+#[clap(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
-    #[clap(short, long, action = ArgAction::Count)]
+    #[clap(short, long, action = ArgAction::Count, global = true)]
     pub verbose: u8,
 
-    #[clap(required = true, last = true, value_parser)]
+    /// Format of the standard output, logs go to the standard error with `json`.
+    #[clap(long, value_enum, default_value = "text", global = true)]
+    pub output: Output,
+
+    /// Write spans of the run to the file, see `--trace-format`.
+    #[clap(long, value_parser, global = true)]
+    pub trace: Option<PathBuf>,
+
+    #[clap(long, value_enum, default_value = "chrome", global = true)]
+    pub trace_format: TraceFormat,
+
+    /// Kill the run once it takes longer than this many seconds.
+    #[clap(long, value_parser, global = true)]
+    pub timeout: Option<u64>,
+
+    /// Skip pushing, like when nothing has changed since the previous run.
+    #[clap(long, action, global = true, conflicts_with_all = &["push-only", "pull-only"])]
+    pub no_push: bool,
+
+    /// Skip pulling.
+    #[clap(long, action, global = true, conflicts_with_all = &["push-only", "pull-only"])]
+    pub no_pull: bool,
+
+    /// Only push, like to warm up the remote machine before going offline.
+    #[clap(long, action, global = true, conflicts_with = "pull-only")]
+    pub push_only: bool,
+
+    /// Only pull, like results of the previous run.
+    #[clap(long, action, global = true)]
+    pub pull_only: bool,
+
+    /// Show what push and pull would transfer and delete, without executing the command.
+    #[clap(long, action, global = true)]
+    pub dry_run: bool,
+
+    /// Execute tasks with `cache: true` even if a cached result matches.
+    #[clap(long, action, global = true)]
+    pub no_cache: bool,
+
+    #[clap(subcommand)]
+    pub action: Option<Action>,
+
+    #[clap(
+        required_unless_present_any = &["push-only", "pull-only"],
+        last = true,
+        value_parser
+    )]
     command: Vec<String>,
 }
 
 impl Args {
     #[inline(always)]
     pub fn command(&self) -> String {
-        self.command.join(" ").trim().to_string()
+        join_command(&self.command)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, ValueEnum)]
+pub enum Output {
+    /// Human-readable log.
+    Text,
+    /// Newline-delimited JSON events.
+    Json,
+}
+
+#[derive(Subcommand)]
+pub enum Action {
+    /// Execute the command on remote machine(s).
+    Run(Run),
+
+    /// Show recent runs with duration percentiles and regressions per command.
+    History(History),
+
+    /// Serve a Mainframer on the local machine over standard input and output, see `remote.agent`.
+    Agent,
+
+    /// Keep pushing changes of the project as they happen, so that runs can skip the push.
+    Watch,
+}
+
+#[derive(clap::Args)]
+pub struct History {
+    /// Number of recent runs to show.
+    #[clap(long, default_value = "10", value_parser)]
+    pub limit: usize,
+}
+
+#[derive(clap::Args)]
+pub struct Run {
+    /// Split the command across this many remote machines from the pool.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub shard: Option<u32>,
+
+    /// Pull each shard into its own `<DIR>/<index>` instead of merging into the project.
+    #[clap(long, value_parser, requires = "shard")]
+    pub shard_dir: Option<PathBuf>,
+
+    /// Task from the config to execute, the command after `--` gets appended to it.
+    #[clap(value_parser)]
+    pub task: Option<String>,
+
+    #[clap(
+        required_unless_present_any = &["task", "push-only", "pull-only"],
+        last = true,
+        value_parser
+    )]
+    command: Vec<String>,
+}
+
+impl Run {
+    #[inline(always)]
+    pub fn command(&self) -> String {
+        join_command(&self.command)
+    }
+}
+
+fn join_command(command: &[String]) -> String {
+    command.join(" ").trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command_without_subcommand() {
+        let args = Args::parse_from(["mainframer", "-v", "--", "./gradlew", "build"]);
+        assert!(args.action.is_none());
+        assert_eq!(args.verbose, 1);
+        assert_eq!(args.output, Output::Text);
+        assert_eq!(args.command(), "./gradlew build");
+    }
+
+    #[test]
+    fn parse_run_with_shard() {
+        let args = Args::parse_from(["mainframer", "run", "--shard", "3", "--", "make", "test"]);
+        match args.action {
+            Some(Action::Run(run)) => {
+                assert_eq!(run.shard, Some(3));
+                assert_eq!(run.shard_dir, None);
+                assert_eq!(run.command(), "make test");
+            }
+            _ => panic!("Expected run subcommand"),
+        }
+    }
+
+    #[test]
+    fn parse_run_task() {
+        let args = Args::parse_from(["mainframer", "run", "test"]);
+        match args.action {
+            Some(Action::Run(run)) => {
+                assert_eq!(run.task, Some(String::from("test")));
+                assert_eq!(run.command(), "");
+            }
+            _ => panic!("Expected run subcommand"),
+        }
+    }
+
+    #[test]
+    fn parse_run_task_with_extra_arguments() {
+        let args = Args::parse_from(["mainframer", "run", "test", "--", "--tests", "Foo"]);
+        match args.action {
+            Some(Action::Run(run)) => {
+                assert_eq!(run.task, Some(String::from("test")));
+                assert_eq!(run.command(), "--tests Foo");
+            }
+            _ => panic!("Expected run subcommand"),
+        }
+    }
+
+    #[test]
+    fn parse_run_without_task_and_command_fails() {
+        assert!(Args::try_parse_from(["mainframer", "run"]).is_err());
+    }
+
+    #[test]
+    fn parse_output_json_after_subcommand() {
+        let args = Args::parse_from(["mainframer", "run", "--output", "json", "--", "make"]);
+        assert_eq!(args.output, Output::Json);
+    }
+
+    #[test]
+    fn parse_timeout() {
+        let args = Args::parse_from(["mainframer", "--timeout", "600", "--", "make"]);
+        assert_eq!(args.timeout, Some(600));
+    }
+
+    #[test]
+    fn parse_pull_only_without_command() {
+        let args = Args::parse_from(["mainframer", "--pull-only"]);
+        assert!(args.pull_only);
+        assert_eq!(args.command(), "");
+    }
+
+    #[test]
+    fn parse_run_push_only_without_command() {
+        let args = Args::parse_from(["mainframer", "run", "--push-only"]);
+        assert!(args.push_only);
+        assert!(matches!(args.action, Some(Action::Run(_))));
+    }
+
+    #[test]
+    fn parse_no_push_and_no_pull() {
+        let args = Args::parse_from(["mainframer", "--no-push", "--no-pull", "--", "make"]);
+        assert!(args.no_push);
+        assert!(args.no_pull);
+    }
+
+    #[test]
+    fn parse_no_push_with_push_only_fails() {
+        assert!(Args::try_parse_from(["mainframer", "--no-push", "--push-only"]).is_err());
+    }
+
+    #[test]
+    fn parse_dry_run_after_subcommand() {
+        let args = Args::parse_from(["mainframer", "run", "--dry-run", "--", "make"]);
+        assert!(args.dry_run);
+    }
+
+    #[test]
+    fn parse_no_cache_after_subcommand() {
+        let args = Args::parse_from(["mainframer", "run", "lint", "--no-cache"]);
+        assert!(args.no_cache);
+    }
+
+    #[test]
+    fn parse_history_without_command() {
+        let args = Args::parse_from(["mainframer", "history", "--limit", "3"]);
+        match args.action {
+            Some(Action::History(history)) => assert_eq!(history.limit, 3),
+            _ => panic!("Expected history subcommand"),
+        }
+    }
+
+    #[test]
+    fn parse_agent_without_command() {
+        let args = Args::parse_from(["mainframer", "agent"]);
+        assert!(matches!(args.action, Some(Action::Agent)));
+    }
+
+    #[test]
+    fn parse_watch_without_command() {
+        let args = Args::parse_from(["mainframer", "watch"]);
+        assert!(matches!(args.action, Some(Action::Watch)));
+    }
+
+    #[test]
+    fn parse_without_command_fails() {
+        assert!(Args::try_parse_from(["mainframer"]).is_err());
     }
 }
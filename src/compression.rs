@@ -0,0 +1,122 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::Compression;
+use crate::summary::format_bytes;
+use crate::sync::RsyncStats;
+
+/// Level of `auto` until the link to the remote machine is measured, cheap and still helps on slow links.
+const MEASURING_LEVEL: i8 = 1;
+
+/// Smaller transfers take as long as connecting and comparing files, not as long as the link needs.
+const MIN_MEASURED_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Compression level for `rsync --compress-level`, `auto` uses the one remembered for the host.
+pub fn level(local_dir_absolute_path: &Path, host: &str, compression: Compression) -> i8 {
+    match compression {
+        Compression::Level(level) => level,
+        Compression::Auto => remembered(local_dir_absolute_path, host).unwrap_or(MEASURING_LEVEL),
+    }
+}
+
+/// Chooses and remembers the level for the host after the first transfer large enough to measure the link.
+pub fn measure(
+    local_dir_absolute_path: &Path,
+    host: &str,
+    compression: Compression,
+    stats: &RsyncStats,
+    duration: Duration,
+) {
+    if compression != Compression::Auto || remembered(local_dir_absolute_path, host).is_some() {
+        return;
+    }
+
+    let bytes = stats.bytes_sent + stats.bytes_received;
+
+    if bytes < MIN_MEASURED_BYTES || duration.is_zero() {
+        return;
+    }
+
+    let throughput = (bytes as f64 / duration.as_secs_f64()) as u64;
+    let level = level_for(throughput);
+
+    tracing::info!(
+        "Measured {}/s to {}, using compression level {} for it.",
+        format_bytes(throughput),
+        host,
+        level
+    );
+
+    let path = file(local_dir_absolute_path, host);
+
+    let result = match path.parent() {
+        Some(dir) => fs::create_dir_all(dir),
+        None => Ok(()),
+    }
+    .and_then(|_| fs::write(&path, level.to_string()));
+
+    // Only costs measuring again next time.
+    if let Err(error) = result {
+        tracing::warn!("Could not write {:?}: {}", path, error);
+    }
+}
+
+/// No compression on LAN, more of it the slower the link gets.
+fn level_for(bytes_per_second: u64) -> i8 {
+    const MIB: u64 = 1024 * 1024;
+
+    match bytes_per_second {
+        speed if speed >= 40 * MIB => 0,
+        speed if speed >= 10 * MIB => 1,
+        speed if speed >= 2 * MIB => 3,
+        _ => 6,
+    }
+}
+
+fn remembered(local_dir_absolute_path: &Path, host: &str) -> Option<i8> {
+    fs::read_to_string(file(local_dir_absolute_path, host))
+        .ok()
+        .and_then(|contents| contents.trim().parse().ok())
+        .filter(|level| (0..=9).contains(level))
+}
+
+/// Within [`crate::sync::LOCAL_STATE`], pulls would delete it otherwise.
+fn file(local_dir_absolute_path: &Path, host: &str) -> PathBuf {
+    local_dir_absolute_path
+        .join(".mainframer")
+        .join("cache")
+        .join("compression")
+        .join(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn level_for_lan() {
+        assert_eq!(level_for(110 * MIB), 0);
+    }
+
+    #[test]
+    fn level_for_slow_links() {
+        assert_eq!(level_for(20 * MIB), 1);
+        assert_eq!(level_for(5 * MIB), 3);
+        assert_eq!(level_for(MIB / 2), 6);
+    }
+
+    #[test]
+    fn level_configured() {
+        assert_eq!(
+            level(Path::new("/nonexistent"), "build-1", Compression::Level(0)),
+            0
+        );
+        assert_eq!(
+            level(Path::new("/nonexistent"), "build-1", Compression::Auto),
+            MEASURING_LEVEL
+        );
+    }
+}
@@ -1,7 +1,12 @@
+use std::collections::BTreeMap;
+use std::fmt;
 use std::{fs, path::Path};
 
-use crate::sync::PullMode;
-use serde::Deserialize;
+use crate::pool::HostSelection;
+use crate::shell;
+use crate::sync::{Delete, PullMode, PushStrategy};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Deserializer};
 
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
 pub struct Config {
@@ -10,6 +15,12 @@ pub struct Config {
     pub push: Push,
     #[serde(default)]
     pub pull: Pull,
+    #[serde(default)]
+    pub hooks: Hooks,
+    #[serde(default)]
+    pub tasks: BTreeMap<String, Task>,
+    #[serde(default)]
+    pub cache: Cache,
 }
 
 impl Config {
@@ -26,84 +37,665 @@ impl Config {
         serde_yaml::from_str::<Config>(contents)
             .map_err(|err| err.to_string())
             .and_then(|config| {
+                if config.remote.candidates().is_empty() {
+                    return Err(String::from("'remote.host' or 'remote.hosts' must be set"));
+                }
+
+                if let Some(name) = invalid_variable_name(&config.remote.env) {
+                    return Err(format!(
+                        "'remote.env' must contain valid variable names, but had '{}'",
+                        name
+                    ));
+                }
+
+                for (task, definition) in &config.tasks {
+                    if let Some(name) = invalid_variable_name(&definition.env) {
+                        return Err(format!(
+                            "'tasks.{}.env' must contain valid variable names, but had '{}'",
+                            task, name
+                        ));
+                    }
+                }
+
+                if let Some(setting) = config.unsupported_by_agent() {
+                    return Err(format!(
+                        "'{}' is not supported with 'remote.agent'",
+                        setting
+                    ));
+                }
+
                 match (
                     config.valid_pull_compression_range(),
                     config.valid_push_compression_range(),
                 ) {
                     (true, true) => Ok(config),
                     (false, _) => Err(format!(
-                        "'pull.compression' must be a positive integer from 1 to 9, but was {}",
+                        "'pull.compression' must be 'auto' or an integer from 0 to 9, but was {}",
                         config.pull.compression
                     )),
                     (_, false) => Err(format!(
-                        "'push.compression' must be a positive integer from 1 to 9, but was {}",
+                        "'push.compression' must be 'auto' or an integer from 0 to 9, but was {}",
                         config.push.compression
                     )),
                 }
             })
     }
 
+    /// Applies settings of the task on top of the config, returns its steps.
+    ///
+    /// `arguments` get appended to the command of the task.
+    pub fn apply_task(&mut self, name: &str, arguments: &str) -> Result<Vec<Step>, String> {
+        let task = match self.tasks.get(name) {
+            Some(task) => task.clone(),
+            None if self.tasks.is_empty() => {
+                return Err(format!(
+                    "Unknown task '{}', 'tasks' are not set in the config",
+                    name
+                ))
+            }
+            None => {
+                return Err(format!(
+                    "Unknown task '{}', available tasks: {}",
+                    name,
+                    self.tasks.keys().cloned().collect::<Vec<_>>().join(", ")
+                ))
+            }
+        };
+
+        if let Some(mode) = task.pull_mode {
+            self.pull.mode = mode;
+        }
+
+        if let Some(paths) = task.pull_paths {
+            self.pull.paths = paths;
+        }
+
+        if task.timeout.is_some() {
+            self.remote.timeout = task.timeout;
+        }
+
+        self.remote.env.extend(task.env);
+
+        match task.command {
+            Some(command) => Ok(Step::default_steps(
+                [command.as_str(), arguments].join(" ").trim().to_string(),
+            )),
+            None if arguments.is_empty() => Ok(task.steps),
+            None => Err(format!(
+                "Task '{}' has steps, arguments can't be appended to it",
+                name
+            )),
+        }
+    }
+
+    /// First setting the agent can't honour, its transfers don't go through `rsync`.
+    fn unsupported_by_agent(&self) -> Option<&'static str> {
+        [
+            ("push.retries", self.push.retries > 0),
+            ("push.bandwidthLimit", self.push.bandwidth_limit.is_some()),
+            ("pull.retries", self.pull.retries > 0),
+            ("pull.bandwidthLimit", self.pull.bandwidth_limit.is_some()),
+        ]
+        .iter()
+        .find(|(_, set)| *set && self.remote.agent.is_some())
+        .map(|(setting, _)| *setting)
+    }
+
     pub fn valid_pull_compression_range(&self) -> bool {
-        (1..=9).contains(&self.pull.compression)
+        self.pull.compression.valid()
     }
 
     pub fn valid_push_compression_range(&self) -> bool {
-        (1..=9).contains(&self.push.compression)
+        self.push.compression.valid()
     }
 }
 
-#[derive(Debug, Default, Eq, PartialEq, Clone, Deserialize)]
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Remote {
+    #[serde(default)]
     pub host: String,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub selection: HostSelection,
+    #[serde(default = "Remote::default_probe_timeout")]
+    pub probe_timeout: u64,
     pub user: Option<String>,
     pub port: Option<String>,
     pub path: Option<String>,
+    pub identity_file: Option<String>,
+    pub proxy_jump: Option<String>,
+    /// `-o` options for `ssh`, like `StrictHostKeyChecking=no`.
+    #[serde(default)]
+    pub ssh_options: Vec<String>,
+    /// Program used instead of `ssh`.
+    pub ssh_command: Option<String>,
+    /// Variables exported for the remote command.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Names or glob patterns like `CI_*` of local variables exported for the remote command.
+    #[serde(default)]
+    pub forward_env: Vec<String>,
+    #[serde(default = "Remote::default_rewrite_paths")]
+    pub rewrite_paths: bool,
+    #[serde(default)]
+    pub path_mappings: Vec<PathMapping>,
+    /// Whether `ssh` and `rsync` share a master connection to the remote machine.
+    #[serde(default = "Remote::default_multiplex")]
+    pub multiplex: bool,
+    /// Seconds, the remote command gets killed after that.
+    pub timeout: Option<u64>,
+    /// Retries after ssh connection errors, off by default since the command might not be idempotent.
+    #[serde(default)]
+    pub retries: u32,
+    /// Seconds before the first retry, doubled after each one.
+    #[serde(default = "Remote::default_retry_backoff")]
+    pub retry_backoff: u64,
+    /// Command starting Mainframer on the remote machine, files and output then go over its single connection.
+    pub agent: Option<String>,
+}
+
+impl Remote {
+    pub fn default_probe_timeout() -> u64 {
+        Self::default().probe_timeout
+    }
+
+    pub fn default_rewrite_paths() -> bool {
+        Self::default().rewrite_paths
+    }
+
+    pub fn default_retry_backoff() -> u64 {
+        Self::default().retry_backoff
+    }
+
+    pub fn default_multiplex() -> bool {
+        Self::default().multiplex
+    }
+
+    /// All configured hosts, `host` goes first.
+    pub fn candidates(&self) -> Vec<String> {
+        let mut candidates: Vec<String> = Vec::new();
+
+        for host in std::iter::once(&self.host).chain(self.hosts.iter()) {
+            if !host.is_empty() && !candidates.contains(host) {
+                candidates.push(host.clone());
+            }
+        }
+
+        candidates
+    }
+}
+
+impl Default for Remote {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            hosts: Vec::new(),
+            selection: HostSelection::default(),
+            probe_timeout: 5,
+            user: None,
+            port: None,
+            path: None,
+            identity_file: None,
+            proxy_jump: None,
+            ssh_options: Vec::new(),
+            ssh_command: None,
+            env: BTreeMap::new(),
+            forward_env: Vec::new(),
+            rewrite_paths: true,
+            path_mappings: Vec::new(),
+            multiplex: true,
+            timeout: None,
+            retries: 0,
+            retry_backoff: 1,
+            agent: None,
+        }
+    }
 }
 
+/// Path prefix on the remote machine and the local one it corresponds to.
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
+pub struct PathMapping {
+    pub remote: String,
+    pub local: String,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Push {
     #[serde(default = "Push::default_compression")]
-    pub compression: i8,
+    pub compression: Compression,
     pub user: Option<String>,
+    #[serde(default)]
+    pub strategy: PushStrategy,
+    /// Seconds, `rsync` gets killed after that.
+    pub timeout: Option<u64>,
+    /// Retries after transient `rsync` errors like dropped connections.
+    #[serde(default)]
+    pub retries: u32,
+    /// Seconds before the first retry, doubled after each one.
+    #[serde(default = "Push::default_retry_backoff")]
+    pub retry_backoff: u64,
+    /// KiB per second, `rsync --bwlimit`.
+    pub bandwidth_limit: Option<u64>,
+    /// Compare files by checksum instead of size and modification time.
+    #[serde(default)]
+    pub checksum: bool,
+    /// Keep partially transferred files so the next transfer resumes them.
+    #[serde(default)]
+    pub partial: bool,
+    /// Compression algorithms by preference, see [`crate::rsync::compress_choice`].
+    #[serde(default)]
+    pub compress_choice: Vec<String>,
+    /// Whether and when remote files missing on the sending side get deleted.
+    #[serde(default)]
+    pub delete: Delete,
+    /// Patterns of remote files never deleted.
+    #[serde(default)]
+    pub protect: Vec<String>,
 }
 
 impl Push {
-    pub fn default_compression() -> i8 {
+    pub fn default_compression() -> Compression {
         Self::default().compression
     }
+
+    pub fn default_retry_backoff() -> u64 {
+        Self::default().retry_backoff
+    }
 }
 
 impl Default for Push {
     fn default() -> Self {
         Self {
-            compression: 3,
+            compression: Compression::Level(3),
             user: None,
+            strategy: PushStrategy::default(),
+            timeout: None,
+            retries: 0,
+            retry_backoff: 1,
+            bandwidth_limit: None,
+            checksum: false,
+            partial: false,
+            compress_choice: Vec::new(),
+            delete: Delete::default(),
+            protect: Vec::new(),
         }
     }
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Pull {
     #[serde(default = "Pull::default_compression")]
-    pub compression: i8,
+    pub compression: Compression,
     #[serde(default)]
     pub mode: PullMode,
     pub user: Option<String>,
+    /// Paths relative to the project to pull, everything if empty.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Seconds, each `rsync` gets killed after that.
+    pub timeout: Option<u64>,
+    /// Retries after transient `rsync` errors like dropped connections.
+    #[serde(default)]
+    pub retries: u32,
+    /// Seconds before the first retry, doubled after each one.
+    #[serde(default = "Pull::default_retry_backoff")]
+    pub retry_backoff: u64,
+    /// KiB per second, `rsync --bwlimit`.
+    pub bandwidth_limit: Option<u64>,
+    /// Compare files by checksum instead of size and modification time.
+    #[serde(default)]
+    pub checksum: bool,
+    /// Keep partially transferred files so the next transfer resumes them.
+    #[serde(default)]
+    pub partial: bool,
+    /// Compression algorithms by preference, see [`crate::rsync::compress_choice`].
+    #[serde(default)]
+    pub compress_choice: Vec<String>,
+    /// Whether and when local files missing on the sending side get deleted.
+    #[serde(default)]
+    pub delete: Delete,
+    /// Patterns of local files never deleted.
+    #[serde(default)]
+    pub protect: Vec<String>,
+    /// Move deleted and overwritten local files to the trash of the run, see [`crate::sync::trash_dir`].
+    #[serde(default)]
+    pub backup: bool,
 }
 
 impl Pull {
-    pub fn default_compression() -> i8 {
+    pub fn default_compression() -> Compression {
         Self::default().compression
     }
+
+    pub fn default_retry_backoff() -> u64 {
+        Self::default().retry_backoff
+    }
 }
 
 impl Default for Pull {
     fn default() -> Self {
         Self {
-            compression: 1,
+            compression: Compression::Level(1),
             mode: PullMode::default(),
             user: None,
+            paths: Vec::new(),
+            timeout: None,
+            retries: 0,
+            retry_backoff: 1,
+            bandwidth_limit: None,
+            checksum: false,
+            partial: false,
+            compress_choice: Vec::new(),
+            delete: Delete::default(),
+            protect: Vec::new(),
+            backup: false,
+        }
+    }
+}
+
+/// Compression level of `rsync`, `auto` picks one per remote machine, see [`crate::compression`].
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Deserialize)]
+#[serde(try_from = "serde_yaml::Value")]
+pub enum Compression {
+    Level(i8),
+    Auto,
+}
+
+impl Compression {
+    fn valid(&self) -> bool {
+        match self {
+            Compression::Level(level) => (0..=9).contains(level),
+            Compression::Auto => true,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Level(level) => write!(f, "{}", level),
+            Compression::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+impl TryFrom<serde_yaml::Value> for Compression {
+    type Error = String;
+
+    fn try_from(value: serde_yaml::Value) -> Result<Self, Self::Error> {
+        match value {
+            serde_yaml::Value::String(value) if value == "auto" => Ok(Compression::Auto),
+            serde_yaml::Value::Number(number) => number
+                .as_i64()
+                .and_then(|level| i8::try_from(level).ok())
+                .map(Compression::Level)
+                .ok_or_else(|| {
+                    format!(
+                        "compression must be 'auto' or an integer from 0 to 9, but was {}",
+                        number
+                    )
+                }),
+            value => Err(format!(
+                "compression must be 'auto' or an integer from 0 to 9, but was {:?}",
+                value
+            )),
+        }
+    }
+}
+
+/// Results of tasks with `cache: true`, see [`crate::result_cache`].
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Cache {
+    /// MiB, least recently used results get evicted above that.
+    #[serde(default = "Cache::default_max_size")]
+    pub max_size: u64,
+}
+
+impl Cache {
+    pub fn default_max_size() -> u64 {
+        Self::default().max_size
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self { max_size: 1024 }
+    }
+}
+
+/// Named command shared in the config, `mainframer run {name}` executes it.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
+#[serde(try_from = "serde_yaml::Value")]
+pub struct Task {
+    /// Pushed, executed and pulled like an ad-hoc command, `None` if the task has steps.
+    pub command: Option<String>,
+    pub steps: Vec<Step>,
+    /// Overrides `pull.mode`.
+    pub pull_mode: Option<PullMode>,
+    /// Overrides `pull.paths`.
+    pub pull_paths: Option<Vec<String>>,
+    /// Added to `remote.env`, overriding variables with the same name.
+    pub env: BTreeMap<String, String>,
+    /// Overrides `remote.timeout`, seconds.
+    pub timeout: Option<u64>,
+    /// Whether a successful result gets cached and replayed for the same inputs.
+    pub cache: bool,
+}
+
+/// Task with settings as written in the config.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct TaskEntry {
+    command: Option<String>,
+    #[serde(default)]
+    steps: Vec<Step>,
+    pull_mode: Option<PullMode>,
+    pull_paths: Option<Vec<String>>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    timeout: Option<u64>,
+    #[serde(default)]
+    cache: bool,
+}
+
+/// Either just the command or the command with settings.
+impl TryFrom<serde_yaml::Value> for Task {
+    type Error = String;
+
+    fn try_from(value: serde_yaml::Value) -> Result<Self, Self::Error> {
+        if let serde_yaml::Value::String(command) = value {
+            return Ok(Task {
+                command: Some(command),
+                steps: Vec::new(),
+                pull_mode: None,
+                pull_paths: None,
+                env: BTreeMap::new(),
+                timeout: None,
+                cache: false,
+            });
+        }
+
+        let entry: TaskEntry = serde_yaml::from_value(value).map_err(|err| err.to_string())?;
+
+        if entry.command.is_some() != entry.steps.is_empty() {
+            return Err(String::from(
+                "task must have either 'command' or non-empty 'steps'",
+            ));
+        }
+
+        Ok(Task {
+            command: entry.command,
+            steps: entry.steps,
+            pull_mode: entry.pull_mode,
+            pull_paths: entry.pull_paths,
+            env: entry.env,
+            timeout: entry.timeout,
+            cache: entry.cache,
+        })
+    }
+}
+
+/// Step of a task, steps run in order.
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
+#[serde(try_from = "serde_yaml::Value")]
+pub struct Step {
+    pub action: StepAction,
+    /// Whether the following steps run and the run succeeds if this step fails.
+    pub continue_on_error: bool,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum StepAction {
+    Push,
+    Exec(String),
+    /// Paths to pull, `pull.paths` if `None`.
+    Pull(Option<Vec<String>>),
+}
+
+impl Step {
+    /// Push, execute the command and pull, like a run without a task.
+    pub fn default_steps(command: String) -> Vec<Step> {
+        [
+            StepAction::Push,
+            StepAction::Exec(command),
+            StepAction::Pull(None),
+        ]
+        .into_iter()
+        .map(|action| Step {
+            action,
+            continue_on_error: false,
+        })
+        .collect()
+    }
+}
+
+/// Step as written in the config: `push`, `pull`, or a map with
+/// `push:`, `exec: {command}` or `pull: [paths]` and `continueOnError`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct StepEntry {
+    #[serde(default, deserialize_with = "present")]
+    push: bool,
+    exec: Option<String>,
+    #[serde(default, deserialize_with = "present_option")]
+    pull: Option<Option<Vec<String>>>,
+    #[serde(default)]
+    continue_on_error: bool,
+}
+
+impl TryFrom<serde_yaml::Value> for Step {
+    type Error = String;
+
+    fn try_from(value: serde_yaml::Value) -> Result<Self, Self::Error> {
+        let entry: StepEntry = match value {
+            serde_yaml::Value::String(action) => match action.as_str() {
+                "push" => StepEntry {
+                    push: true,
+                    exec: None,
+                    pull: None,
+                    continue_on_error: false,
+                },
+                "pull" => StepEntry {
+                    push: false,
+                    exec: None,
+                    pull: Some(None),
+                    continue_on_error: false,
+                },
+                _ => {
+                    return Err(format!(
+                        "step must be 'push', 'pull' or have 'exec', but was '{}'",
+                        action
+                    ))
+                }
+            },
+            value => serde_yaml::from_value(value).map_err(|err| err.to_string())?,
+        };
+
+        let action = match (entry.push, entry.exec, entry.pull) {
+            (true, None, None) => StepAction::Push,
+            (false, Some(command), None) => StepAction::Exec(command),
+            (false, None, Some(paths)) => StepAction::Pull(paths),
+            _ => {
+                return Err(String::from(
+                    "step must have exactly one of 'push', 'exec' or 'pull'",
+                ))
+            }
+        };
+
+        Ok(Step {
+            action,
+            continue_on_error: entry.continue_on_error,
+        })
+    }
+}
+
+/// Tells a key without a value, like `push:`, from a missing one.
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    IgnoredAny::deserialize(deserializer).map(|_| true)
+}
+
+fn present_option<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+fn invalid_variable_name(env: &BTreeMap<String, String>) -> Option<&String> {
+    env.keys().find(|name| !shell::is_variable_name(name))
+}
+
+/// Commands around the phases of the run, each phase runs its hooks in order.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hooks {
+    #[serde(default)]
+    pub pre_push: Vec<Hook>,
+    #[serde(default)]
+    pub post_push: Vec<Hook>,
+    #[serde(default)]
+    pub pre_exec: Vec<Hook>,
+    #[serde(default)]
+    pub post_exec: Vec<Hook>,
+    #[serde(default)]
+    pub pre_pull: Vec<Hook>,
+    #[serde(default)]
+    pub post_pull: Vec<Hook>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
+#[serde(try_from = "HookEntry")]
+pub enum Hook {
+    /// Command for the local machine, runs in the project directory.
+    Local(String),
+    /// Command for the remote machine, runs in the project directory there.
+    Remote(String),
+}
+
+/// Hook as written in the config: `local: {command}` or `remote: {command}`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HookEntry {
+    local: Option<String>,
+    remote: Option<String>,
+}
+
+impl TryFrom<HookEntry> for Hook {
+    type Error = String;
+
+    fn try_from(entry: HookEntry) -> Result<Self, Self::Error> {
+        match (entry.local, entry.remote) {
+            (Some(command), None) => Ok(Hook::Local(command)),
+            (None, Some(command)) => Ok(Hook::Remote(command)),
+            _ => Err(String::from(
+                "hook must have either 'local' or 'remote' command",
+            )),
         }
     }
 }
@@ -132,14 +724,17 @@ pull:
                     ..Default::default()
                 },
                 push: Push {
-                    compression: 5,
+                    compression: Compression::Level(5),
                     ..Default::default()
                 },
                 pull: Pull {
-                    compression: 2,
+                    compression: Compression::Level(2),
                     mode: PullMode::Serial,
                     ..Default::default()
                 },
+                hooks: Hooks::default(),
+                tasks: BTreeMap::new(),
+                cache: Cache::default(),
             })
         );
     }
@@ -164,14 +759,17 @@ pull:
                     ..Default::default()
                 },
                 push: Push {
-                    compression: 5,
+                    compression: Compression::Level(5),
                     ..Default::default()
                 },
                 pull: Pull {
-                    compression: 2,
+                    compression: Compression::Level(2),
                     mode: PullMode::Serial,
                     ..Default::default()
                 },
+                hooks: Hooks::default(),
+                tasks: BTreeMap::new(),
+                cache: Cache::default(),
             })
         );
     }
@@ -196,14 +794,17 @@ pull:
                     ..Default::default()
                 },
                 push: Push {
-                    compression: 5,
+                    compression: Compression::Level(5),
                     ..Default::default()
                 },
                 pull: Pull {
-                    compression: 2,
+                    compression: Compression::Level(2),
                     mode: PullMode::Serial,
                     ..Default::default()
                 },
+                hooks: Hooks::default(),
+                tasks: BTreeMap::new(),
+                cache: Cache::default(),
             })
         );
     }
@@ -223,19 +824,19 @@ remote:
                 },
                 push: Push::default(),
                 pull: Pull::default(),
+                hooks: Hooks::default(),
+                tasks: BTreeMap::new(),
+                cache: Cache::default(),
             })
         );
     }
 
     #[test]
     fn parse_config_from_str_compression_valid_range() {
-        let mut destinations: Vec<String> = Vec::new();
-
-        destinations.push(String::from("push"));
-        destinations.push(String::from("pull"));
+        let destinations: Vec<String> = vec![String::from("push"), String::from("pull")];
 
         for destination in destinations {
-            for compression_level in 1..9 {
+            for compression_level in 0..=9 {
                 let content = format!(
                     "
 remote:
@@ -255,7 +856,7 @@ remote:
                         },
                         push: if destination == "push" {
                             Push {
-                                compression: compression_level,
+                                compression: Compression::Level(compression_level),
                                 ..Default::default()
                             }
                         } else {
@@ -263,12 +864,15 @@ remote:
                         },
                         pull: if destination == "pull" {
                             Pull {
-                                compression: compression_level,
+                                compression: Compression::Level(compression_level),
                                 ..Default::default()
                             }
                         } else {
                             Pull::default()
                         },
+                        hooks: Hooks::default(),
+                        tasks: BTreeMap::new(),
+                        cache: Cache::default(),
                     })
                 );
             }
@@ -277,16 +881,9 @@ remote:
 
     #[test]
     fn parse_config_from_str_compression_invalid_range() {
-        let mut destinations: Vec<String> = Vec::new();
-
-        destinations.push(String::from("push"));
-        destinations.push(String::from("pull"));
+        let destinations: Vec<String> = vec![String::from("push"), String::from("pull")];
 
-        let mut invalid_compression_levels: Vec<i64> = Vec::new();
-
-        invalid_compression_levels.push(0);
-        invalid_compression_levels.push(10);
-        invalid_compression_levels.push(-1);
+        let invalid_compression_levels: Vec<i64> = vec![10, -1];
 
         for destination in destinations {
             for compression_level in &invalid_compression_levels {
@@ -303,7 +900,7 @@ remote:
                 assert_eq!(
                     Config::from_file_contents(&content),
                     Err(format!(
-                        "'{}.compression' must be a positive integer from 1 to 9, but was {}",
+                        "'{}.compression' must be 'auto' or an integer from 0 to 9, but was {}",
                         destination, compression_level
                     ))
                 );
@@ -311,6 +908,52 @@ remote:
         }
     }
 
+    #[test]
+    fn parse_config_from_str_compression_auto() {
+        let content = "
+remote:
+    host: computer1
+push:
+  compression: auto
+";
+        assert_eq!(
+            Config::from_file_contents(content).map(|config| config.push.compression),
+            Ok(Compression::Auto)
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_delete_and_protect() {
+        let content = "
+remote:
+    host: computer1
+push:
+  delete: after
+pull:
+  delete: false
+  protect:
+    - local.properties
+  backup: true
+";
+        let config = Config::from_file_contents(content).unwrap();
+
+        assert_eq!(config.push.delete, Delete::After);
+        assert_eq!(config.pull.delete, Delete::Never);
+        assert_eq!(config.pull.protect, vec![String::from("local.properties")]);
+        assert!(config.pull.backup);
+    }
+
+    #[test]
+    fn parse_config_from_str_delete_invalid() {
+        let content = "
+remote:
+    host: computer1
+pull:
+  delete: sometimes
+";
+        assert!(Config::from_file_contents(content).is_err());
+    }
+
     #[test]
     fn parse_config_from_str_push_compression_not_an_integer() {
         let content = "
@@ -342,10 +985,533 @@ pull:
                     mode: PullMode::Parallel,
                     ..Default::default()
                 },
+                hooks: Hooks::default(),
+                tasks: BTreeMap::new(),
+                cache: Cache::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_remote_hosts_pool() {
+        let content = "
+remote:
+  hosts:
+    - computer1
+    - computer2
+  selection: leastLoad
+  probeTimeout: 2
+";
+        assert_eq!(
+            Config::from_file_contents(content),
+            Ok(Config {
+                remote: Remote {
+                    hosts: vec![String::from("computer1"), String::from("computer2")],
+                    selection: HostSelection::LeastLoad,
+                    probe_timeout: 2,
+                    ..Default::default()
+                },
+                push: Push::default(),
+                pull: Pull::default(),
+                hooks: Hooks::default(),
+                tasks: BTreeMap::new(),
+                cache: Cache::default(),
             })
         );
     }
 
+    #[test]
+    fn parse_config_from_str_path_mappings() {
+        let content = "
+remote:
+  host: computer1
+  rewritePaths: false
+  pathMappings:
+    - remote: /opt/android-sdk
+      local: /Users/me/Library/Android/sdk
+";
+        assert_eq!(
+            Config::from_file_contents(content),
+            Ok(Config {
+                remote: Remote {
+                    host: String::from("computer1"),
+                    rewrite_paths: false,
+                    path_mappings: vec![PathMapping {
+                        remote: String::from("/opt/android-sdk"),
+                        local: String::from("/Users/me/Library/Android/sdk"),
+                    }],
+                    ..Default::default()
+                },
+                push: Push::default(),
+                pull: Pull::default(),
+                hooks: Hooks::default(),
+                tasks: BTreeMap::new(),
+                cache: Cache::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_timeouts() {
+        let content = "
+remote:
+  host: computer1
+  timeout: 600
+push:
+  timeout: 60
+pull:
+  timeout: 30
+";
+        assert_eq!(
+            Config::from_file_contents(content),
+            Ok(Config {
+                remote: Remote {
+                    host: String::from("computer1"),
+                    timeout: Some(600),
+                    ..Default::default()
+                },
+                push: Push {
+                    timeout: Some(60),
+                    ..Default::default()
+                },
+                pull: Pull {
+                    timeout: Some(30),
+                    ..Default::default()
+                },
+                hooks: Hooks::default(),
+                tasks: BTreeMap::new(),
+                cache: Cache::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_retries() {
+        let content = "
+remote:
+  host: computer1
+  retries: 1
+push:
+  retries: 3
+  retryBackoff: 5
+pull:
+  retries: 2
+";
+        assert_eq!(
+            Config::from_file_contents(content),
+            Ok(Config {
+                remote: Remote {
+                    host: String::from("computer1"),
+                    retries: 1,
+                    ..Default::default()
+                },
+                push: Push {
+                    retries: 3,
+                    retry_backoff: 5,
+                    ..Default::default()
+                },
+                pull: Pull {
+                    retries: 2,
+                    ..Default::default()
+                },
+                hooks: Hooks::default(),
+                tasks: BTreeMap::new(),
+                cache: Cache::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_agent_with_retries() {
+        let content = "
+remote:
+  host: computer1
+  agent: ~/bin/mainframer
+pull:
+  retries: 2
+";
+        assert_eq!(
+            Config::from_file_contents(content),
+            Err(String::from(
+                "'pull.retries' is not supported with 'remote.agent'"
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_agent_with_bandwidth_limit() {
+        let content = "
+remote:
+  host: computer1
+  agent: ~/bin/mainframer
+push:
+  bandwidthLimit: 1024
+";
+        assert_eq!(
+            Config::from_file_contents(content),
+            Err(String::from(
+                "'push.bandwidthLimit' is not supported with 'remote.agent'"
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_ssh_settings() {
+        let content = "
+remote:
+  host: computer1
+  identityFile: ~/.ssh/ci
+  proxyJump: bastion.example.com
+  sshOptions:
+    - StrictHostKeyChecking=no
+  sshCommand: /usr/local/bin/ssh
+";
+        assert_eq!(
+            Config::from_file_contents(content),
+            Ok(Config {
+                remote: Remote {
+                    host: String::from("computer1"),
+                    identity_file: Some(String::from("~/.ssh/ci")),
+                    proxy_jump: Some(String::from("bastion.example.com")),
+                    ssh_options: vec![String::from("StrictHostKeyChecking=no")],
+                    ssh_command: Some(String::from("/usr/local/bin/ssh")),
+                    ..Default::default()
+                },
+                push: Push::default(),
+                pull: Pull::default(),
+                hooks: Hooks::default(),
+                tasks: BTreeMap::new(),
+                cache: Cache::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_env() {
+        let content = "
+remote:
+  host: computer1
+  env:
+    LANG: C.UTF-8
+    GRADLE_OPTS: -Xmx4g
+  forwardEnv:
+    - CI_*
+";
+        assert_eq!(
+            Config::from_file_contents(content),
+            Ok(Config {
+                remote: Remote {
+                    host: String::from("computer1"),
+                    env: [
+                        (String::from("LANG"), String::from("C.UTF-8")),
+                        (String::from("GRADLE_OPTS"), String::from("-Xmx4g")),
+                    ]
+                    .into_iter()
+                    .collect(),
+                    forward_env: vec![String::from("CI_*")],
+                    ..Default::default()
+                },
+                push: Push::default(),
+                pull: Pull::default(),
+                hooks: Hooks::default(),
+                tasks: BTreeMap::new(),
+                cache: Cache::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_env_invalid_name() {
+        let content = "
+remote:
+  host: computer1
+  env:
+    BAD-NAME: value
+";
+        assert_eq!(
+            Config::from_file_contents(content),
+            Err(String::from(
+                "'remote.env' must contain valid variable names, but had 'BAD-NAME'"
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_hooks() {
+        let content = "
+remote:
+  host: computer1
+hooks:
+  prePush:
+    - local: ./gradlew generateSources
+  preExec:
+    - remote: docker compose up -d
+  postPull:
+    - local: notify-send done
+    - remote: docker compose down
+";
+        assert_eq!(
+            Config::from_file_contents(content).map(|config| config.hooks),
+            Ok(Hooks {
+                pre_push: vec![Hook::Local(String::from("./gradlew generateSources"))],
+                pre_exec: vec![Hook::Remote(String::from("docker compose up -d"))],
+                post_pull: vec![
+                    Hook::Local(String::from("notify-send done")),
+                    Hook::Remote(String::from("docker compose down")),
+                ],
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_hook_local_and_remote() {
+        let content = "
+remote:
+  host: computer1
+hooks:
+  postExec:
+    - local: make report
+      remote: make report
+";
+        assert!(Config::from_file_contents(content)
+            .unwrap_err()
+            .contains("hook must have either 'local' or 'remote' command"));
+    }
+
+    #[test]
+    fn parse_config_from_str_tasks() {
+        let content = "
+remote:
+  host: computer1
+tasks:
+  test: ./gradlew test --offline
+  report:
+    command: ./gradlew jacocoTestReport
+    pullMode: parallel
+    pullPaths:
+      - build/reports
+    env:
+      CI: 'true'
+    timeout: 600
+    cache: true
+";
+        let tasks = Config::from_file_contents(content).unwrap().tasks;
+
+        assert_eq!(
+            tasks.get("test"),
+            Some(&Task {
+                command: Some(String::from("./gradlew test --offline")),
+                steps: Vec::new(),
+                pull_mode: None,
+                pull_paths: None,
+                env: BTreeMap::new(),
+                timeout: None,
+                cache: false,
+            })
+        );
+        assert_eq!(
+            tasks.get("report"),
+            Some(&Task {
+                command: Some(String::from("./gradlew jacocoTestReport")),
+                steps: Vec::new(),
+                pull_mode: Some(PullMode::Parallel),
+                pull_paths: Some(vec![String::from("build/reports")]),
+                env: [(String::from("CI"), String::from("true"))]
+                    .into_iter()
+                    .collect(),
+                timeout: Some(600),
+                cache: true,
+            })
+        );
+    }
+
+    #[test]
+    fn apply_task_overrides_settings() {
+        let content = "
+remote:
+  host: computer1
+  timeout: 60
+  env:
+    LANG: C.UTF-8
+    CI: 'false'
+tasks:
+  report:
+    command: ./gradlew jacocoTestReport
+    pullMode: parallel
+    pullPaths:
+      - build/reports
+    env:
+      CI: 'true'
+";
+        let mut config = Config::from_file_contents(content).unwrap();
+
+        assert_eq!(
+            config.apply_task("report", "--info"),
+            Ok(Step::default_steps(String::from(
+                "./gradlew jacocoTestReport --info"
+            )))
+        );
+        assert_eq!(config.pull.mode, PullMode::Parallel);
+        assert_eq!(config.pull.paths, vec![String::from("build/reports")]);
+        assert_eq!(config.remote.timeout, Some(60));
+        assert_eq!(
+            config.remote.env,
+            [
+                (String::from("CI"), String::from("true")),
+                (String::from("LANG"), String::from("C.UTF-8")),
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+
+    #[test]
+    fn apply_task_unknown() {
+        let content = "
+remote:
+  host: computer1
+tasks:
+  build: make
+  test: make test
+";
+        let mut config = Config::from_file_contents(content).unwrap();
+
+        assert_eq!(
+            config.apply_task("lint", ""),
+            Err(String::from(
+                "Unknown task 'lint', available tasks: build, test"
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_task_steps() {
+        let content = "
+remote:
+  host: computer1
+tasks:
+  instrumentation:
+    steps:
+      - push
+      - exec: ./gradlew assembleDebug
+      - pull:
+          - app/build/outputs/apk
+      - push:
+        continueOnError: true
+      - exec: ./gradlew connectedCheck
+        continueOnError: true
+      - pull
+";
+        let mut config = Config::from_file_contents(content).unwrap();
+
+        let step = |action, continue_on_error| Step {
+            action,
+            continue_on_error,
+        };
+
+        assert_eq!(
+            config.apply_task("instrumentation", ""),
+            Ok(vec![
+                step(StepAction::Push, false),
+                step(
+                    StepAction::Exec(String::from("./gradlew assembleDebug")),
+                    false
+                ),
+                step(
+                    StepAction::Pull(Some(vec![String::from("app/build/outputs/apk")])),
+                    false
+                ),
+                step(StepAction::Push, true),
+                step(
+                    StepAction::Exec(String::from("./gradlew connectedCheck")),
+                    true
+                ),
+                step(StepAction::Pull(None), false),
+            ])
+        );
+        assert_eq!(
+            config.apply_task("instrumentation", "--info"),
+            Err(String::from(
+                "Task 'instrumentation' has steps, arguments can't be appended to it"
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_task_step_with_two_actions() {
+        let content = "
+remote:
+  host: computer1
+tasks:
+  test:
+    steps:
+      - exec: make
+        pull:
+";
+        assert!(Config::from_file_contents(content)
+            .unwrap_err()
+            .contains("step must have exactly one of 'push', 'exec' or 'pull'"));
+    }
+
+    #[test]
+    fn parse_config_from_str_task_without_command_and_steps() {
+        let content = "
+remote:
+  host: computer1
+tasks:
+  test:
+    timeout: 60
+";
+        assert!(Config::from_file_contents(content)
+            .unwrap_err()
+            .contains("task must have either 'command' or non-empty 'steps'"));
+    }
+
+    #[test]
+    fn parse_config_from_str_task_env_invalid_name() {
+        let content = "
+remote:
+  host: computer1
+tasks:
+  test:
+    command: make test
+    env:
+      BAD-NAME: value
+";
+        assert_eq!(
+            Config::from_file_contents(content),
+            Err(String::from(
+                "'tasks.test.env' must contain valid variable names, but had 'BAD-NAME'"
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_config_from_str_no_remote_hosts() {
+        let content = "
+remote:
+  user: user1
+";
+        assert_eq!(
+            Config::from_file_contents(content),
+            Err(String::from("'remote.host' or 'remote.hosts' must be set"))
+        );
+    }
+
+    #[test]
+    fn remote_candidates_host_first_without_duplicates() {
+        let remote = Remote {
+            host: String::from("computer1"),
+            hosts: vec![String::from("computer2"), String::from("computer1")],
+            ..Default::default()
+        };
+        assert_eq!(
+            remote.candidates(),
+            vec![String::from("computer1"), String::from("computer2")]
+        );
+    }
+
     #[test]
     fn parse_config_from_str_only_pull_mode_unsupported_value() {
         let content = "
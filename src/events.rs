@@ -0,0 +1,225 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::sync::RsyncStats;
+
+/// Version of the event structure, bumped on incompatible changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables printing events to stdout as newline-delimited JSON.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum Event<'a> {
+    #[serde(rename_all = "camelCase")]
+    PushStarted { host: &'a str },
+
+    #[serde(rename_all = "camelCase")]
+    PushFinished {
+        host: &'a str,
+        success: bool,
+        duration_ms: u64,
+        #[serde(flatten)]
+        stats: Option<&'a RsyncStats>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<&'a str>,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    RemoteOutput {
+        host: &'a str,
+        stream: Stream,
+        line: &'a str,
+    },
+
+    /// Files changed by the remote command relative to the project, only with the agent.
+    #[serde(rename_all = "camelCase")]
+    RemoteChanged { host: &'a str, paths: &'a [String] },
+
+    #[serde(rename_all = "camelCase")]
+    ExecFinished {
+        host: &'a str,
+        success: bool,
+        exit_code: Option<i32>,
+        duration_ms: u64,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        timed_out: bool,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    PullIteration {
+        host: &'a str,
+        success: bool,
+        duration_ms: u64,
+        #[serde(flatten)]
+        stats: Option<&'a RsyncStats>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<&'a str>,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    PullFinished {
+        host: &'a str,
+        success: bool,
+        duration_ms: u64,
+    },
+
+    #[serde(rename_all = "camelCase")]
+    Summary { success: bool, duration_ms: u64 },
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope<'a> {
+    version: u32,
+    timestamp_ms: u64,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Prints the event if JSON output is enabled.
+pub fn emit(event: &Event) {
+    if !enabled() {
+        return;
+    }
+
+    let line = to_json(event, SystemTime::now());
+
+    // Lock to keep lines from different threads intact.
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let _ = writeln!(stdout, "{}", line);
+    let _ = stdout.flush();
+}
+
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+fn to_json(event: &Event, time: SystemTime) -> String {
+    serde_json::to_string(&Envelope {
+        version: SCHEMA_VERSION,
+        timestamp_ms: time
+            .duration_since(UNIX_EPOCH)
+            .map(millis)
+            .unwrap_or_default(),
+        event,
+    })
+    .expect("Could not serialize event")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1500)
+    }
+
+    #[test]
+    fn to_json_push_finished() {
+        let stats = RsyncStats {
+            files: 10,
+            files_transferred: 2,
+            total_size: 680,
+            bytes_sent: 300,
+            bytes_received: 40,
+        };
+
+        assert_eq!(
+            to_json(
+                &Event::PushFinished {
+                    host: "computer1",
+                    success: true,
+                    duration_ms: 10,
+                    stats: Some(&stats),
+                    message: None,
+                },
+                time()
+            ),
+            r#"{"version":1,"timestampMs":1500,"event":"pushFinished","host":"computer1","success":true,"durationMs":10,"files":10,"filesTransferred":2,"totalSize":680,"bytesSent":300,"bytesReceived":40}"#
+        );
+    }
+
+    #[test]
+    fn to_json_remote_output() {
+        assert_eq!(
+            to_json(
+                &Event::RemoteOutput {
+                    host: "computer1",
+                    stream: Stream::Stderr,
+                    line: "warning: \"x\"",
+                },
+                time()
+            ),
+            r#"{"version":1,"timestampMs":1500,"event":"remoteOutput","host":"computer1","stream":"stderr","line":"warning: \"x\""}"#
+        );
+    }
+
+    #[test]
+    fn to_json_remote_changed() {
+        assert_eq!(
+            to_json(
+                &Event::RemoteChanged {
+                    host: "computer1",
+                    paths: &[String::from("build/reports/index.html")],
+                },
+                time()
+            ),
+            r#"{"version":1,"timestampMs":1500,"event":"remoteChanged","host":"computer1","paths":["build/reports/index.html"]}"#
+        );
+    }
+
+    #[test]
+    fn to_json_exec_finished_timed_out() {
+        assert_eq!(
+            to_json(
+                &Event::ExecFinished {
+                    host: "computer1",
+                    success: false,
+                    exit_code: None,
+                    duration_ms: 7,
+                    timed_out: true,
+                },
+                time()
+            ),
+            r#"{"version":1,"timestampMs":1500,"event":"execFinished","host":"computer1","success":false,"exitCode":null,"durationMs":7,"timedOut":true}"#
+        );
+    }
+
+    #[test]
+    fn to_json_exec_finished_without_exit_code() {
+        assert_eq!(
+            to_json(
+                &Event::ExecFinished {
+                    host: "computer1",
+                    success: false,
+                    exit_code: None,
+                    duration_ms: 7,
+                    timed_out: false,
+                },
+                time()
+            ),
+            r#"{"version":1,"timestampMs":1500,"event":"execFinished","host":"computer1","success":false,"exitCode":null,"durationMs":7}"#
+        );
+    }
+}
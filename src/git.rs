@@ -0,0 +1,146 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use serde::{Deserialize, Serialize};
+
+/// State of the last successful push, kept in the project directory on the remote machine.
+pub const STATE_FILE: &str = ".mainframer-push.json";
+
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PushState {
+    /// Commit checked out locally at the time of the push.
+    pub commit: String,
+    /// Files that differed from the commit: modified, deleted and untracked but not ignored ones.
+    pub dirty: Vec<String>,
+    /// Push ignore rules, files they used to exclude are unknown to the remote machine.
+    pub ignore: Vec<String>,
+}
+
+impl PushState {
+    /// Current state of the local working tree.
+    pub fn current(dir: &Path, ignore: Vec<String>) -> Result<Self, String> {
+        let commit = git(dir, &["rev-parse", "--verify", "HEAD"])?
+            .trim()
+            .to_string();
+
+        let dirty: BTreeSet<String> = paths(&git(
+            dir,
+            &[
+                "diff",
+                "--name-only",
+                "-z",
+                "--no-renames",
+                "--relative",
+                "HEAD",
+            ],
+        )?)
+        .chain(paths(&git(
+            dir,
+            &["ls-files", "-z", "--others", "--exclude-standard"],
+        )?))
+        .collect();
+
+        Ok(Self {
+            commit,
+            dirty: dirty.into_iter().collect(),
+            ignore,
+        })
+    }
+}
+
+/// Files that might differ between the remote machine at `previous` and the local machine at `current`,
+/// `None` if the difference is unknown, like after a commit the local repository doesn't have.
+pub fn changed_files(
+    dir: &Path,
+    previous: &PushState,
+    current: &PushState,
+) -> Result<Option<Vec<String>>, String> {
+    if previous.ignore != current.ignore {
+        return Ok(None);
+    }
+
+    let known = git(
+        dir,
+        &["cat-file", "-e", &format!("{}^{{commit}}", previous.commit)],
+    );
+
+    if known.is_err() {
+        return Ok(None);
+    }
+
+    let committed = git(
+        dir,
+        &[
+            "diff",
+            "--name-only",
+            "-z",
+            "--no-renames",
+            "--relative",
+            &previous.commit,
+            &current.commit,
+        ],
+    )?;
+
+    let files: BTreeSet<String> = paths(&committed)
+        .chain(previous.dirty.iter().cloned())
+        .chain(current.dirty.iter().cloned())
+        .collect();
+
+    Ok(Some(files.into_iter().collect()))
+}
+
+fn paths(output: &str) -> impl Iterator<Item = String> + '_ {
+    output
+        .split('\0')
+        .filter(|path| !path.is_empty())
+        .map(String::from)
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|error| format!("Could not execute git: {}", error))?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_split_by_nul() {
+        assert_eq!(
+            paths("src/main.rs\0docs/read me.md\0").collect::<Vec<_>>(),
+            vec!["src/main.rs", "docs/read me.md"]
+        );
+    }
+
+    #[test]
+    fn changed_files_unknown_after_ignore_change() {
+        let previous = PushState {
+            commit: String::from("abc"),
+            dirty: Vec::new(),
+            ignore: vec![String::from("build")],
+        };
+        let current = PushState {
+            ignore: Vec::new(),
+            ..previous.clone()
+        };
+
+        assert_eq!(changed_files(Path::new("."), &previous, &current), Ok(None));
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::summary::format_bytes;
+use crate::time::format_duration;
+
+/// Minimum number of runs of a command to look for regressions.
+const REGRESSION_MIN_RUNS: usize = 6;
+
+/// How much slower recent runs have to be to be reported as a regression.
+const REGRESSION_RATIO: f64 = 1.2;
+
+/// Single run of a command, stored as a line of `.mainframer/history.jsonl`.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    pub timestamp_ms: u64,
+    pub command: String,
+    pub host: String,
+    pub success: bool,
+    /// `None` if the command was not executed or was terminated.
+    pub exit_code: Option<i32>,
+    pub push_ms: Option<u64>,
+    pub exec_ms: Option<u64>,
+    pub pull_ms: Option<u64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Whether a phase or the whole run has timed out.
+    #[serde(default)]
+    pub timed_out: bool,
+}
+
+impl Record {
+    pub fn new(command: &str, host: &str) -> Self {
+        Self {
+            timestamp_ms: now_ms(),
+            command: command.to_string(),
+            host: host.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn total_ms(&self) -> u64 {
+        self.push_ms.unwrap_or_default()
+            + self.exec_ms.unwrap_or_default()
+            + self.pull_ms.unwrap_or_default()
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+pub fn file(local_dir_absolute_path: &Path) -> PathBuf {
+    local_dir_absolute_path
+        .join(".mainframer")
+        .join("history.jsonl")
+}
+
+pub fn append(local_dir_absolute_path: &Path, record: &Record) -> io::Result<()> {
+    let line = serde_json::to_string(record).map_err(io::Error::from)?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file(local_dir_absolute_path))?;
+
+    // Single write keeps lines from concurrent shards intact.
+    file.write_all(format!("{}\n", line).as_bytes())
+}
+
+/// Reads all records, skipping lines that can't be parsed.
+pub fn load(local_dir_absolute_path: &Path) -> Vec<Record> {
+    fs::read_to_string(file(local_dir_absolute_path))
+        .map(|contents| parse(&contents))
+        .unwrap_or_default()
+}
+
+fn parse(contents: &str) -> Vec<Record> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// Nearest-rank percentile, `None` for no values.
+pub fn percentile(values: &[u64], percent: u64) -> Option<u64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+
+    let rank = (percent as f64 / 100.0 * sorted.len() as f64).ceil() as usize;
+
+    Some(sorted[rank.max(1) - 1])
+}
+
+/// Human-readable report: recent runs, percentiles per command and regressions.
+pub fn report(records: &[Record], limit: usize, now_ms: u64) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+
+    if records.is_empty() {
+        lines.push(String::from("No runs recorded yet."));
+        return lines;
+    }
+
+    lines.push(String::from("Recent runs:"));
+
+    for record in records.iter().rev().take(limit) {
+        lines.push(format!(
+            "  {} ago  {}  {}  exit {}  push {}  exec {}  pull {}  sent {}  received {}",
+            format_duration(Duration::from_millis(
+                now_ms.saturating_sub(record.timestamp_ms)
+            )),
+            record.command,
+            record.host,
+            record
+                .exit_code
+                .map_or_else(|| String::from("—"), |code| code.to_string()),
+            format_millis(record.push_ms),
+            format_millis(record.exec_ms),
+            format_millis(record.pull_ms),
+            format_bytes(record.bytes_sent),
+            format_bytes(record.bytes_received),
+        ));
+    }
+
+    lines.push(String::new());
+    lines.push(String::from("Per command:"));
+
+    let commands = by_command(records);
+
+    for (command, runs) in &commands {
+        let totals: Vec<u64> = runs.iter().map(|record| record.total_ms()).collect();
+        let pushes: Vec<u64> = runs.iter().filter_map(|record| record.push_ms).collect();
+        let pulls: Vec<u64> = runs.iter().filter_map(|record| record.pull_ms).collect();
+
+        lines.push(format!(
+            "  {}  runs {}  total p50 {} p90 {}  push p50 {} p90 {}  pull p50 {} p90 {}",
+            command,
+            runs.len(),
+            format_millis(percentile(&totals, 50)),
+            format_millis(percentile(&totals, 90)),
+            format_millis(percentile(&pushes, 50)),
+            format_millis(percentile(&pushes, 90)),
+            format_millis(percentile(&pulls, 50)),
+            format_millis(percentile(&pulls, 90)),
+        ));
+    }
+
+    let regressions: Vec<String> = commands
+        .iter()
+        .flat_map(|(command, runs)| {
+            [
+                ("push", regression(runs, |record| record.push_ms)),
+                ("exec", regression(runs, |record| record.exec_ms)),
+                ("pull", regression(runs, |record| record.pull_ms)),
+            ]
+            .into_iter()
+            .filter_map(move |(phase, regression)| {
+                regression.map(|(before, after)| {
+                    format!(
+                        "  {}  {} p50 grew from {} to {}",
+                        command,
+                        phase,
+                        format_millis(Some(before)),
+                        format_millis(Some(after))
+                    )
+                })
+            })
+        })
+        .collect();
+
+    if !regressions.is_empty() {
+        lines.push(String::new());
+        lines.push(String::from("Regressions:"));
+        lines.extend(regressions);
+    }
+
+    lines
+}
+
+/// Groups records by command keeping the order of the first appearance.
+fn by_command(records: &[Record]) -> Vec<(String, Vec<&Record>)> {
+    let mut order: Vec<String> = Vec::new();
+    let mut groups: HashMap<String, Vec<&Record>> = HashMap::new();
+
+    for record in records {
+        if !groups.contains_key(&record.command) {
+            order.push(record.command.clone());
+        }
+        groups
+            .entry(record.command.clone())
+            .or_default()
+            .push(record);
+    }
+
+    order
+        .into_iter()
+        .map(|command| {
+            let runs = groups.remove(&command).unwrap_or_default();
+            (command, runs)
+        })
+        .collect()
+}
+
+/// Compares median of older and newer halves of successful runs, returns both if newer is slower.
+fn regression(runs: &[&Record], phase: fn(&Record) -> Option<u64>) -> Option<(u64, u64)> {
+    let values: Vec<u64> = runs
+        .iter()
+        .filter(|record| record.success)
+        .filter_map(|record| phase(record))
+        .collect();
+
+    if values.len() < REGRESSION_MIN_RUNS {
+        return None;
+    }
+
+    let (older, newer) = values.split_at(values.len() / 2);
+    let before = percentile(older, 50)?;
+    let after = percentile(newer, 50)?;
+
+    if after as f64 > before as f64 * REGRESSION_RATIO {
+        Some((before, after))
+    } else {
+        None
+    }
+}
+
+fn format_millis(millis: Option<u64>) -> String {
+    millis.map_or_else(
+        || String::from("—"),
+        |millis| format_duration(Duration::from_millis(millis)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(command: &str, push_ms: u64) -> Record {
+        Record {
+            timestamp_ms: 1000,
+            command: command.to_string(),
+            host: String::from("computer1"),
+            success: true,
+            exit_code: Some(0),
+            push_ms: Some(push_ms),
+            exec_ms: Some(1000),
+            pull_ms: Some(100),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_skips_broken_lines() {
+        let contents = format!(
+            "{}\nyooo\n\n{}\n",
+            serde_json::to_string(&record("make", 10)).unwrap(),
+            serde_json::to_string(&record("make test", 20)).unwrap()
+        );
+
+        assert_eq!(
+            parse(&contents),
+            vec![record("make", 10), record("make test", 20)]
+        );
+    }
+
+    #[test]
+    fn percentile_empty() {
+        assert_eq!(percentile(&[], 50), None);
+    }
+
+    #[test]
+    fn percentile_nearest_rank() {
+        let values = [50, 10, 40, 20, 30, 60, 70, 80, 90, 100];
+        assert_eq!(percentile(&values, 50), Some(50));
+        assert_eq!(percentile(&values, 90), Some(90));
+        assert_eq!(percentile(&values, 100), Some(100));
+        assert_eq!(percentile(&[7], 90), Some(7));
+    }
+
+    #[test]
+    fn regression_push_grows() {
+        let records: Vec<Record> = [100, 110, 90, 200, 210, 190]
+            .iter()
+            .map(|push_ms| record("make", *push_ms))
+            .collect();
+        let runs: Vec<&Record> = records.iter().collect();
+
+        assert_eq!(regression(&runs, |record| record.push_ms), Some((100, 200)));
+        assert_eq!(regression(&runs, |record| record.exec_ms), None);
+    }
+
+    #[test]
+    fn regression_not_enough_runs() {
+        let records: Vec<Record> = [100, 200, 300]
+            .iter()
+            .map(|push_ms| record("make", *push_ms))
+            .collect();
+        let runs: Vec<&Record> = records.iter().collect();
+
+        assert_eq!(regression(&runs, |record| record.push_ms), None);
+    }
+
+    #[test]
+    fn report_no_records() {
+        assert_eq!(report(&[], 10, 0), vec!["No runs recorded yet."]);
+    }
+
+    #[test]
+    fn report_groups_by_command() {
+        let records = vec![record("make", 100), record("make test", 200)];
+
+        assert_eq!(
+            report(&records, 1, 61000),
+            vec![
+                "Recent runs:",
+                "  1 minute 0 seconds ago  make test  computer1  exit 0  push 0.200 seconds  exec 1.000 seconds  pull 0.100 seconds  sent 0 B  received 0 B",
+                "",
+                "Per command:",
+                "  make  runs 1  total p50 1.200 seconds p90 1.200 seconds  push p50 0.100 seconds p90 0.100 seconds  pull p50 0.100 seconds p90 0.100 seconds",
+                "  make test  runs 1  total p50 1.300 seconds p90 1.300 seconds  push p50 0.200 seconds p90 0.200 seconds  pull p50 0.100 seconds p90 0.100 seconds",
+            ]
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::config::{Config, Hook};
+use crate::history::Record;
+use crate::remote_command;
+use crate::trace;
+
+/// Where hooks run and what they get in addition to the run state.
+pub struct Context<'a> {
+    pub local_dir_absolute_path: &'a Path,
+    pub config: &'a Config,
+    pub project_dir_on_remote_machine: &'a str,
+    /// Variables of the run, like the shard index.
+    pub env: &'a [(String, String)],
+}
+
+/// Runs the hooks in order, stops at the first failing one.
+///
+/// `success` is the status of the run so far, passed to hooks with other run details.
+pub fn run(
+    name: &str,
+    hooks: &[Hook],
+    context: &Context,
+    record: &Record,
+    success: bool,
+) -> Result<(), String> {
+    if hooks.is_empty() {
+        return Ok(());
+    }
+
+    let _span = tracing::info_span!(target: trace::TARGET, "hook", name).entered();
+
+    let env = [context.env.to_vec(), variables(name, record, success)].concat();
+
+    for hook in hooks {
+        match hook {
+            Hook::Local(command) => {
+                tracing::info!("Running {} hook: {}", name, command);
+                run_local(command, context.local_dir_absolute_path, &env)
+                    .map_err(|reason| format!("{} hook '{}' failed: {}", name, command, reason))?;
+            }
+            Hook::Remote(command) => {
+                tracing::info!("Running {} hook on remote machine: {}", name, command);
+                remote_command::execute_hook(
+                    command,
+                    context.config,
+                    context.project_dir_on_remote_machine,
+                    env.clone(),
+                )
+                .map_err(|err| format!("{} hook '{}' failed: {}", name, command, err))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Run details for hooks, durations and exit code only once known.
+fn variables(name: &str, record: &Record, success: bool) -> Vec<(String, String)> {
+    let mut variables = vec![
+        (String::from("MAINFRAMER_HOOK"), name.to_string()),
+        (String::from("MAINFRAMER_HOST"), record.host.clone()),
+        (
+            String::from("MAINFRAMER_STATUS"),
+            String::from(if success { "success" } else { "failure" }),
+        ),
+    ];
+
+    let optional = [
+        (
+            "MAINFRAMER_EXIT_CODE",
+            record.exit_code.map(|code| code.to_string()),
+        ),
+        (
+            "MAINFRAMER_PUSH_MS",
+            record.push_ms.map(|ms| ms.to_string()),
+        ),
+        (
+            "MAINFRAMER_EXEC_MS",
+            record.exec_ms.map(|ms| ms.to_string()),
+        ),
+        (
+            "MAINFRAMER_PULL_MS",
+            record.pull_ms.map(|ms| ms.to_string()),
+        ),
+    ];
+
+    for (variable, value) in optional {
+        if let Some(value) = value {
+            variables.push((variable.to_string(), value));
+        }
+    }
+
+    variables
+}
+
+fn run_local(command: &str, dir: &Path, env: &[(String, String)]) -> Result<(), String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(dir)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|error| error.to_string())?;
+
+    // Standard output is reserved for events in JSON mode, hook output goes to the log.
+    for line in String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .chain(String::from_utf8_lossy(&output.stderr).lines())
+    {
+        tracing::info!("{}", line);
+    }
+
+    match output.status.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(format!("exit code {}", code)),
+        None => Err(String::from("terminated")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variables_of_finished_exec() {
+        let record = Record {
+            host: String::from("computer1"),
+            exit_code: Some(2),
+            push_ms: Some(1500),
+            exec_ms: Some(3000),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            variables("postExec", &record, false),
+            vec![
+                (String::from("MAINFRAMER_HOOK"), String::from("postExec")),
+                (String::from("MAINFRAMER_HOST"), String::from("computer1")),
+                (String::from("MAINFRAMER_STATUS"), String::from("failure")),
+                (String::from("MAINFRAMER_EXIT_CODE"), String::from("2")),
+                (String::from("MAINFRAMER_PUSH_MS"), String::from("1500")),
+                (String::from("MAINFRAMER_EXEC_MS"), String::from("3000")),
+            ]
+        );
+    }
+}
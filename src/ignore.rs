@@ -51,4 +51,106 @@ impl Ignore {
         ]
         .concat()
     }
+
+    /// Whether push rules exclude the path relative to the project, or one of its parent directories.
+    pub fn excludes_push(&self, relative_path: &str, is_dir: bool) -> bool {
+        excluded(&self.push(), relative_path, is_dir)
+    }
+}
+
+/// Matches the path against rsync exclude patterns: `/` anchors to the project,
+/// trailing `/` matches only directories, `*` and `?` stay within a path component and `**` doesn't.
+pub fn excluded(patterns: &[String], relative_path: &str, is_dir: bool) -> bool {
+    let components: Vec<&str> = relative_path
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .collect();
+
+    (0..components.len()).any(|index| {
+        let path = components[..=index].join("/");
+        let path_is_dir = index + 1 < components.len() || is_dir;
+
+        patterns
+            .iter()
+            .any(|pattern| pattern_matches(pattern, &path, path_is_dir))
+    })
+}
+
+fn pattern_matches(pattern: &str, path: &str, is_dir: bool) -> bool {
+    let (pattern, dir_only) = match pattern.strip_suffix('/') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+
+    if dir_only && !is_dir {
+        return false;
+    }
+
+    if let Some(anchored) = pattern.strip_prefix('/') {
+        return glob(anchored.as_bytes(), path.as_bytes());
+    }
+
+    if pattern.contains('/') || pattern.contains("**") {
+        // Matches the trailing components of the path.
+        return std::iter::once(0)
+            .chain(path.match_indices('/').map(|(index, _)| index + 1))
+            .any(|start| glob(pattern.as_bytes(), &path.as_bytes()[start..]));
+    }
+
+    let name = path.rsplit('/').next().unwrap_or(path);
+    glob(pattern.as_bytes(), name.as_bytes())
+}
+
+fn glob(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|index| glob(rest, &path[index..])),
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|&index| index == 0 || path[index - 1] != b'/')
+            .any(|index| glob(rest, &path[index..])),
+        [b'?', rest @ ..] => {
+            matches!(path.first(), Some(c) if *c != b'/') && glob(rest, &path[1..])
+        }
+        [c, rest @ ..] => path.first() == Some(c) && glob(rest, &path[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn excluded_by_name_at_any_depth() {
+        let patterns = patterns(&["build", "*.iml"]);
+        assert!(excluded(&patterns, "app/build/out.jar", false));
+        assert!(excluded(&patterns, "app/app.iml", false));
+        assert!(!excluded(&patterns, "app/builder.rs", false));
+    }
+
+    #[test]
+    fn excluded_anchored() {
+        let patterns = patterns(&["/build"]);
+        assert!(excluded(&patterns, "build/out.jar", false));
+        assert!(!excluded(&patterns, "app/build/out.jar", false));
+    }
+
+    #[test]
+    fn excluded_directory_only() {
+        let patterns = patterns(&["out/"]);
+        assert!(excluded(&patterns, "out/classes", false));
+        assert!(excluded(&patterns, "out", true));
+        assert!(!excluded(&patterns, "out", false));
+    }
+
+    #[test]
+    fn excluded_with_wildcards() {
+        let patterns = patterns(&["src/*.tmp", "**/generated"]);
+        assert!(excluded(&patterns, "app/src/a.tmp", false));
+        assert!(!excluded(&patterns, "src/nested/a.tmp", false));
+        assert!(excluded(&patterns, "a/b/generated/c.rs", false));
+    }
 }
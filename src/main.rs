@@ -3,24 +3,47 @@ extern crate crossbeam_channel;
 
 use std::env;
 use std::fs;
-use std::process;
-use std::time::Instant;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use args::Args;
+use args::{Action, Args, Output};
 use clap::Parser;
 use config::*;
+use events::Event;
 use ignore::*;
-use sync::PullMode;
+use process::Deadline;
+use sync::PullTarget;
 use time::*;
 use tracing::Level;
-use tracing_subscriber::FmtSubscriber;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
 
+mod agent;
 mod args;
+mod compression;
 mod config;
+mod events;
+mod git;
+mod history;
+mod hooks;
 mod ignore;
+mod manifest;
+mod pipeline;
+mod pool;
+mod process;
 mod remote_command;
+mod result_cache;
+mod rsync;
+mod shell;
+mod ssh;
+mod summary;
 mod sync;
 mod time;
+mod trace;
+mod watch;
 
 // TODO use Reactive Streams instead of Channels.
 
@@ -29,6 +52,15 @@ fn main() {
 
     let args = Args::parse();
 
+    // Standard output carries the protocol, logs would corrupt it.
+    if let Some(Action::Agent) = &args.action {
+        if let Err(error) = agent::serve() {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let log_level = match args.verbose {
         0 => Level::INFO,
         1 => Level::DEBUG,
@@ -36,103 +68,396 @@ fn main() {
         _ => Level::TRACE,
     };
 
-    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
+    // Standard output is reserved for events in JSON mode.
+    let writer = match args.output {
+        Output::Text => BoxMakeWriter::new(std::io::stdout),
+        Output::Json => {
+            events::enable();
+            BoxMakeWriter::new(std::io::stderr)
+        }
+    };
+
+    // Phase spans are only interesting for the trace, they would clutter every log line.
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_filter(filter_fn(move |metadata| {
+            metadata.level() <= &log_level
+                && !(metadata.is_span() && metadata.target() == trace::TARGET)
+        }));
 
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Setting default subscriber failed!");
+    if let Some(path) = args.trace.clone() {
+        trace::init(path, args.trace_format);
+    }
 
-    // let main_span = info_span!("main.rs");
-    // let _guard = main_span.enter();
+    // Always present, a `None` layer would disable the filtered log layer above.
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::registry()
+            .with(fmt_layer)
+            .with(trace::TraceLayer),
+    )
+    .expect("Setting default subscriber failed!");
 
     tracing::info!(":: Mainframer v{}", env!("CARGO_PKG_VERSION"));
 
+    let _main_span = tracing::info_span!(target: trace::TARGET, "mainframer").entered();
+
     let local_dir_absolute_path = match env::current_dir() {
         Err(_) => exit_with_error("Could not resolve working directory, make sure it exists and user has enough permissions to work with it.", 1),
         Ok(value) => fs::canonicalize(value).unwrap()
     };
 
+    if let Some(Action::History(history)) = &args.action {
+        print_history(&local_dir_absolute_path, history.limit, args.output);
+        return;
+    }
+
     let mut config_file = local_dir_absolute_path.clone();
     config_file.push(".mainframer/config.yml");
 
-    let config = match Config::from_path(&config_file) {
+    let mut config = match tracing::info_span!(target: trace::TARGET, "config")
+        .in_scope(|| Config::from_path(&config_file))
+    {
         Err(error) => exit_with_error(&error, 1),
         Ok(value) => value,
     };
 
     let ignore = Ignore::from_working_dir(&local_dir_absolute_path);
 
-    tracing::info!("Pushing...");
-
-    match sync::push(&local_dir_absolute_path, &config, &ignore, args.verbose) {
-        Err(err) => exit_with_error(
-            &format!(
-                "Push failed: {}, took {}",
-                err.message,
-                format_duration(err.duration)
-            ),
-            1,
-        ),
-        Ok(ok) => tracing::info!("Push done: took {}.", format_duration(ok.duration)),
+    if let Some(Action::Watch) = &args.action {
+        if let Err(error) = watch::run(&local_dir_absolute_path, &config, &ignore, args.verbose) {
+            exit_with_error(&error, 1);
+        }
+        return;
     }
 
-    match config.pull.mode {
-        PullMode::Serial => tracing::info!("Executing command on remote machine..."),
-        PullMode::Parallel => {
-            tracing::info!("Executing command on remote machine (pulling in parallel)...")
+    let (mut steps, shard, shard_dir) = match &args.action {
+        None => (Step::default_steps(args.command()), None, None),
+        Some(Action::Run(run)) => {
+            let steps = match &run.task {
+                None => Step::default_steps(run.command()),
+                Some(task) => match config.apply_task(task, &run.command()) {
+                    Err(error) => exit_with_error(&error, 1),
+                    Ok(steps) => steps,
+                },
+            };
+
+            (steps, run.shard, run.shard_dir.clone())
         }
-    }
+        Some(Action::History(_)) => unreachable!("History is printed before loading the config"),
+        Some(Action::Watch) => unreachable!("Watch runs before selecting steps"),
+        Some(Action::Agent) => unreachable!("Agent runs before logging is set up"),
+    };
 
-    let mut remote_command_readers = remote_command::execute_remote_command(
-        args.command(),
-        config.clone(),
-        sync::project_dir_on_remote_machine(&config, &local_dir_absolute_path),
-        2,
+    steps = pipeline::select(
+        steps,
+        pipeline::Phases {
+            push: !args.no_push && !args.pull_only,
+            exec: !args.push_only && !args.pull_only,
+            pull: !args.no_pull && !args.push_only,
+        },
     );
 
-    let pull_finished_rx = sync::pull(
-        &local_dir_absolute_path,
-        config.clone(),
-        ignore,
-        &config.pull.mode,
-        remote_command_readers.pop().unwrap(),
-        args.verbose,
-    );
+    if steps.is_empty() {
+        exit_with_error("Nothing to run, all steps are skipped.", 1);
+    }
 
-    let remote_command_result = remote_command_readers.pop().unwrap().recv().unwrap();
+    let deadline = Deadline::after(args.timeout.map(Duration::from_secs), total_start);
 
-    match remote_command_result {
-        Err(ref err) => {
-            tracing::error!("Execution failed: took {}.", format_duration(err.duration));
-            tracing::info!("Pulling...");
+    // Sharded runs and dry runs always execute.
+    let cached = match &args.action {
+        Some(Action::Run(run)) if shard.is_none() && !args.no_cache && !args.dry_run => {
+            matches!(&run.task, Some(task) if matches!(config.tasks.get(task), Some(task) if task.cache))
         }
-        Ok(ref ok) => {
-            tracing::info!("Execution done: took {}.", format_duration(ok.duration));
-            tracing::info!("Pulling...");
+        _ => false,
+    };
+
+    let lookup = match cached {
+        false => None,
+        true => match tracing::info_span!(target: trace::TARGET, "cache")
+            .in_scope(|| result_cache::lookup(&local_dir_absolute_path, &config, &ignore, &steps))
+        {
+            Err(error) => {
+                tracing::warn!("Could not look up the cached result: {}", error);
+                None
+            }
+            Ok(lookup) => Some(lookup),
+        },
+    };
+
+    let (success, timed_out) = match (shard, lookup) {
+        (_, Some((lookup, Some(entry)))) => {
+            tracing::info!(
+                "Replaying the result of '{}' cached {} ago, use --no-cache to execute it.",
+                entry.command,
+                format_duration(Duration::from_millis(
+                    history::now_ms().saturating_sub(entry.created_ms)
+                ))
+            );
+
+            match result_cache::replay(&local_dir_absolute_path, &lookup, &entry) {
+                Err(error) => {
+                    tracing::error!("{}", error);
+                    (false, false)
+                }
+                Ok(()) => (true, false),
+            }
         }
-    }
+        (None, lookup) => {
+            let watched_host = match steps.first() {
+                Some(Step {
+                    action: StepAction::Push,
+                    ..
+                }) if !args.dry_run => {
+                    watch::synced_host(&local_dir_absolute_path, &config, deadline)
+                        .filter(|host| config.remote.candidates().contains(host))
+                }
+                _ => None,
+            };
 
-    let pull_result = pull_finished_rx
-        .recv()
-        .expect("Could not receive remote_to_local_sync_result");
+            if let Some(host) = &watched_host {
+                tracing::info!(
+                    "Skipping push, mainframer watch has pushed all changes to {}.",
+                    host
+                );
+                steps.remove(0);
+            }
 
-    let total_duration = total_start.elapsed();
+            let pushes = steps
+                .iter()
+                .any(|step| matches!(step.action, StepAction::Push));
+
+            // Without a push the files are on the machine of the previous run.
+            let previous_host = match (watched_host, pushes) {
+                (Some(host), _) => Some(host),
+                (None, true) => None,
+                (None, false) => pool::previous_host(&config, &local_dir_absolute_path),
+            };
+
+            config.remote.host = match previous_host {
+                Some(host) => host,
+                None => match tracing::info_span!(target: trace::TARGET, "select host")
+                    .in_scope(|| pool::select_host(&config, &local_dir_absolute_path))
+                {
+                    Err(error) => exit_with_error(&error, 1),
+                    Ok(value) => value,
+                },
+            };
+
+            let run = pipeline::Run {
+                local_dir_absolute_path: &local_dir_absolute_path,
+                config: &config,
+                ignore: &ignore,
+                env: Vec::new(),
+                stdin: true,
+                pull_target: PullTarget::project(&local_dir_absolute_path),
+                deadline,
+                dry_run: args.dry_run,
+                verbose: args.verbose,
+            };
 
-    match pull_result {
-        Err(ref err) => tracing::error!(
-            "Pull failed: {}, took {}.",
-            err.message,
-            format_duration(err.duration)
+            if lookup.is_some() {
+                remote_command::start_capture();
+            }
+
+            let record = pipeline::execute(&run, &steps);
+
+            if let Some((lookup, _)) = lookup {
+                let output = remote_command::finish_capture();
+
+                if record.success {
+                    if let Err(error) = result_cache::store(
+                        &local_dir_absolute_path,
+                        &config,
+                        &ignore,
+                        lookup,
+                        &record,
+                        output,
+                    ) {
+                        tracing::warn!("Could not cache the result: {}", error);
+                    }
+                }
+            }
+
+            if !args.dry_run {
+                record_history(&local_dir_absolute_path, &record);
+            }
+            (record.success, record.timed_out)
+        }
+        (Some(shard_count), _) => execute_sharded(
+            &local_dir_absolute_path,
+            &config,
+            &ignore,
+            &steps,
+            shard_count as usize,
+            shard_dir.as_deref(),
+            deadline,
+            args.dry_run,
+            args.verbose,
         ),
-        Ok(ref ok) => tracing::info!("Pull done: took {}", format_duration(ok.duration)),
-    }
+    };
+
+    let total_duration = total_start.elapsed();
 
-    if remote_command_result.is_err() || pull_result.is_err() {
+    events::emit(&Event::Summary {
+        success,
+        duration_ms: events::millis(total_duration),
+    });
+
+    if success {
+        tracing::info!("Success: took {}.", format_duration(total_duration));
+        trace::finish();
+    } else if timed_out {
+        exit_with_error(
+            &format!("Timed out: took {}.", format_duration(total_duration)),
+            process::TIMEOUT_EXIT_CODE,
+        );
+    } else {
         exit_with_error(
             &format!("Failure: took {}.", format_duration(total_duration)),
             1,
         );
-    } else {
-        tracing::info!("Success: took {}.", format_duration(total_duration));
+    }
+}
+
+/// Executes the command on several remote machines at once, each one gets its shard index.
+///
+/// Returns whether all shards have succeeded and whether any of them has timed out.
+#[allow(clippy::too_many_arguments)]
+fn execute_sharded(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    steps: &[Step],
+    shard_count: usize,
+    shard_dir: Option<&Path>,
+    deadline: Deadline,
+    dry_run: bool,
+    verbose: u8,
+) -> (bool, bool) {
+    let hosts = match pool::select_hosts(config, local_dir_absolute_path, shard_count) {
+        Err(error) => {
+            tracing::error!("{}", error);
+            return (false, false);
+        }
+        Ok(value) => value,
+    };
+
+    let shards: Vec<thread::JoinHandle<Option<history::Record>>> = hosts
+        .into_iter()
+        .enumerate()
+        .map(|(index, host)| {
+            let mut config = config.clone();
+            config.remote.host = host;
+
+            let pull_target = match shard_dir {
+                // Shards produce different files, deleting would remove results of other shards.
+                None => PullTarget {
+                    delete: false,
+                    ..PullTarget::project(local_dir_absolute_path)
+                },
+                Some(shard_dir) => PullTarget {
+                    dir: local_dir_absolute_path
+                        .join(shard_dir)
+                        .join(index.to_string()),
+                    delete: true,
+                },
+            };
+
+            let env = vec![
+                (String::from("MAINFRAMER_SHARD_INDEX"), index.to_string()),
+                (
+                    String::from("MAINFRAMER_SHARD_COUNT"),
+                    shard_count.to_string(),
+                ),
+            ];
+
+            let local_dir_absolute_path = local_dir_absolute_path.to_path_buf();
+            let ignore = ignore.clone();
+            let steps = steps.to_vec();
+            let span = tracing::info_span!("shard", index, host = %config.remote.host);
+
+            thread::spawn(move || {
+                let _guard = span.enter();
+
+                if let Err(error) = fs::create_dir_all(&pull_target.dir) {
+                    tracing::error!("Could not create {:?}: {}", pull_target.dir, error);
+                    return None;
+                }
+
+                let run = pipeline::Run {
+                    local_dir_absolute_path: &local_dir_absolute_path,
+                    config: &config,
+                    ignore: &ignore,
+                    env,
+                    stdin: false,
+                    pull_target,
+                    deadline,
+                    dry_run,
+                    verbose,
+                };
+
+                Some(pipeline::execute(&run, &steps))
+            })
+        })
+        .collect();
+
+    let results: Vec<(bool, bool)> = shards
+        .into_iter()
+        .map(|shard| match shard.join().unwrap_or(None) {
+            None => (false, false),
+            Some(record) => {
+                if !dry_run {
+                    record_history(local_dir_absolute_path, &record);
+                }
+                (record.success, record.timed_out)
+            }
+        })
+        .collect();
+
+    for (index, (success, timed_out)) in results.iter().enumerate() {
+        if *timed_out {
+            tracing::error!("Shard {} of {} timed out.", index, shard_count);
+        } else if !success {
+            tracing::error!("Shard {} of {} failed.", index, shard_count);
+        }
+    }
+
+    (
+        results.iter().all(|(success, _)| *success),
+        results.iter().any(|(_, timed_out)| *timed_out),
+    )
+}
+
+fn print_history(local_dir_absolute_path: &Path, limit: usize, output: Output) {
+    let records = history::load(local_dir_absolute_path);
+
+    match output {
+        Output::Text => {
+            for line in history::report(&records, limit, history::now_ms()) {
+                println!("{}", line);
+            }
+        }
+        Output::Json => {
+            let skip = records.len().saturating_sub(limit);
+
+            for record in &records[skip..] {
+                println!(
+                    "{}",
+                    serde_json::to_string(record).expect("Could not serialize history record")
+                );
+            }
+        }
+    }
+}
+
+fn record_history(local_dir_absolute_path: &Path, record: &history::Record) {
+    // Runs without a command, like `--push-only`, have nothing to compare with.
+    if record.command.is_empty() {
+        return;
+    }
+
+    if let Err(error) = history::append(local_dir_absolute_path, record) {
+        tracing::warn!("Could not record the run to history: {}", error);
     }
 }
 
@@ -140,5 +465,6 @@ fn exit_with_error(message: &str, code: i32) -> ! {
     if !message.is_empty() {
         tracing::error!("{}", message);
     }
-    process::exit(code);
+    trace::finish();
+    std::process::exit(code);
 }
@@ -0,0 +1,481 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::ignore::{self, Ignore};
+use crate::sync;
+
+/// Held from loading the cache to saving it, shards scan and acknowledge from several threads.
+static LOCK: Mutex<()> = Mutex::new(());
+
+static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+
+/// Local files as of the previous scan and the ones each remote machine has acknowledged.
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Cache {
+    files: BTreeMap<String, FileState>,
+    #[serde(default)]
+    acknowledged: BTreeMap<String, Snapshot>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileState {
+    size: u64,
+    /// Modification time, nanoseconds since the Unix epoch.
+    mtime: u64,
+    /// Empty until [`scan`] hashes the file.
+    #[serde(default)]
+    hash: String,
+    /// Empty until [`Blocks`] hashes the file.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    blocks: Vec<String>,
+}
+
+/// Hashes of the pushed files by path, along with settings that decide what the remote machine has.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    remote_dir: String,
+    ignore: Vec<String>,
+    files: BTreeMap<String, String>,
+}
+
+/// Current local snapshot and files to push since the one the remote machine has acknowledged,
+/// `None` for all files if the remote machine hasn't acknowledged any.
+pub fn plan(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+) -> Result<(Snapshot, Option<Vec<String>>), String> {
+    let exclude = ignore.as_ref().map(Ignore::push).unwrap_or_default();
+
+    let snapshot = Snapshot {
+        remote_dir: sync::project_dir_on_remote_machine(config, local_dir_absolute_path),
+        files: scan(local_dir_absolute_path, &exclude, &[])?,
+        ignore: exclude,
+    };
+
+    let changed = match load(local_dir_absolute_path)
+        .acknowledged
+        .get(&config.remote.host)
+    {
+        None => {
+            tracing::info!("No previous push to the remote machine is known, pushing all files.");
+            None
+        }
+        Some(acknowledged) => changed_files(acknowledged, &snapshot),
+    };
+
+    Ok((snapshot, changed))
+}
+
+/// Hashes of the project files by path, not excluded and within `paths` if set.
+///
+/// Files keeping their size and modification time since the previous scan are not hashed again.
+pub fn scan(
+    local_dir_absolute_path: &Path,
+    exclude: &[String],
+    paths: &[String],
+) -> Result<BTreeMap<String, String>, String> {
+    let _lock = LOCK.lock().unwrap();
+    let cache = load(local_dir_absolute_path);
+
+    let mut files = BTreeMap::new();
+
+    walk(
+        local_dir_absolute_path,
+        exclude,
+        paths,
+        &mut |path, full_path, metadata| {
+            let mtime = mtime(metadata)?;
+            let cached = cache
+                .files
+                .get(&path)
+                .filter(|state| state.size == metadata.len() && state.mtime == mtime);
+
+            let hash = match cached {
+                Some(state) if !state.hash.is_empty() => state.hash.clone(),
+                _ => match hash(full_path, metadata) {
+                    // Files might vanish while a build is running.
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+                    result => result?,
+                },
+            };
+
+            files.insert(
+                path,
+                FileState {
+                    size: metadata.len(),
+                    mtime,
+                    hash,
+                    blocks: cached.map(|state| state.blocks.clone()).unwrap_or_default(),
+                },
+            );
+            Ok(())
+        },
+    )
+    .map_err(|error| format!("Could not scan the project: {}", error))?;
+
+    let hashes = files
+        .iter()
+        .map(|(path, state)| (path.clone(), state.hash.clone()))
+        .collect();
+
+    store(local_dir_absolute_path, cache, files);
+
+    Ok(hashes)
+}
+
+/// Block hashes of the agent, reused from the cache of [`scan`] while files keep their size and modification time.
+pub struct Blocks {
+    _lock: MutexGuard<'static, ()>,
+    cache: Cache,
+    files: BTreeMap<String, FileState>,
+}
+
+impl Blocks {
+    pub fn load(local_dir_absolute_path: &Path) -> Self {
+        let lock = LOCK.lock().unwrap();
+
+        Self {
+            _lock: lock,
+            cache: load(local_dir_absolute_path),
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Block hashes of the regular file, `hash_blocks` only runs if it has changed since they were cached.
+    pub fn hashes(
+        &mut self,
+        path: String,
+        metadata: &fs::Metadata,
+        hash_blocks: impl FnOnce() -> io::Result<Vec<String>>,
+    ) -> io::Result<Vec<String>> {
+        let mtime = mtime(metadata)?;
+        let cached = self
+            .cache
+            .files
+            .get(&path)
+            .filter(|state| state.size == metadata.len() && state.mtime == mtime);
+
+        let blocks = match cached {
+            Some(state) if !state.blocks.is_empty() => state.blocks.clone(),
+            _ => hash_blocks()?,
+        };
+
+        self.files.insert(
+            path,
+            FileState {
+                size: metadata.len(),
+                mtime,
+                hash: cached.map(|state| state.hash.clone()).unwrap_or_default(),
+                blocks: blocks.clone(),
+            },
+        );
+
+        Ok(blocks)
+    }
+
+    pub fn save(self, local_dir_absolute_path: &Path) {
+        store(local_dir_absolute_path, self.cache, self.files);
+    }
+}
+
+fn mtime(metadata: &fs::Metadata) -> io::Result<u64> {
+    Ok(metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|mtime| mtime.as_nanos() as u64)
+        .unwrap_or_default())
+}
+
+/// Saves the scanned files into the cache, the lock has to be held.
+fn store(local_dir_absolute_path: &Path, mut cache: Cache, files: BTreeMap<String, FileState>) {
+    // Scans of other paths keep their files unless those are gone.
+    cache.files.retain(|path, _| {
+        !files.contains_key(path)
+            && fs::symlink_metadata(local_dir_absolute_path.join(path)).is_ok()
+    });
+
+    cache.files.extend(files);
+
+    // Only costs hashing again next time.
+    if let Err(error) = save(local_dir_absolute_path, &cache) {
+        tracing::warn!("{}", error);
+    }
+}
+
+/// Records the snapshot as the one the remote machine has after a successful push.
+pub fn acknowledge(
+    local_dir_absolute_path: &Path,
+    host: &str,
+    snapshot: Snapshot,
+) -> Result<(), String> {
+    let _lock = LOCK.lock().unwrap();
+    let mut cache = load(local_dir_absolute_path);
+    cache.acknowledged.insert(host.to_string(), snapshot);
+    save(local_dir_absolute_path, &cache)
+}
+
+/// Files added, changed or deleted since the acknowledged snapshot, `None` if it is of other settings.
+fn changed_files(acknowledged: &Snapshot, current: &Snapshot) -> Option<Vec<String>> {
+    if acknowledged.remote_dir != current.remote_dir || acknowledged.ignore != current.ignore {
+        return None;
+    }
+
+    let changed = current
+        .files
+        .iter()
+        .filter(|(path, hash)| acknowledged.files.get(*path) != Some(hash))
+        .map(|(path, _)| path.clone());
+
+    let deleted = acknowledged
+        .files
+        .keys()
+        .filter(|path| !current.files.contains_key(*path))
+        .cloned();
+
+    Some(changed.chain(deleted).collect())
+}
+
+fn hash(path: &Path, metadata: &fs::Metadata) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+
+    if metadata.file_type().is_symlink() {
+        hasher.update(b"link:");
+        hasher.update(fs::read_link(path)?.as_os_str().as_bytes());
+    } else {
+        let mut file = fs::File::open(path)?;
+        let mut buffer = [0; 64 * 1024];
+
+        loop {
+            match file.read(&mut buffer)? {
+                0 => break,
+                read => hasher.update(&buffer[..read]),
+            }
+        }
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn file(local_dir_absolute_path: &Path) -> PathBuf {
+    local_dir_absolute_path
+        .join(".mainframer")
+        .join("cache")
+        .join("manifest")
+}
+
+/// Cache of the previous runs, empty if missing or unreadable.
+fn load(local_dir_absolute_path: &Path) -> Cache {
+    fs::read(file(local_dir_absolute_path))
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default()
+}
+
+fn save(local_dir_absolute_path: &Path, cache: &Cache) -> Result<(), String> {
+    let path = file(local_dir_absolute_path);
+    let json = serde_json::to_vec(cache).map_err(|error| error.to_string())?;
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|error| format!("Could not create {:?}: {}", dir, error))?;
+    }
+
+    // Renamed into place, an interrupted write would lose the acknowledged snapshots.
+    // Unique per write, other processes of the project might be saving at the same time.
+    let temp = path.with_extension(format!(
+        "{}-{}.tmp",
+        std::process::id(),
+        NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    fs::write(&temp, json)
+        .and_then(|_| fs::rename(&temp, &path))
+        .map_err(|error| format!("Could not write {:?}: {}", path, error))
+}
+
+/// Calls `visit` with the relative path, full path and metadata of every file and symlink of the directory
+/// not excluded and within `paths` if set, excluded directories are skipped along with their content.
+///
+/// Local state of Mainframer is always skipped, see [`sync::LOCAL_STATE`].
+pub fn walk(
+    root: &Path,
+    exclude: &[String],
+    paths: &[String],
+    visit: &mut dyn FnMut(String, &Path, &fs::Metadata) -> io::Result<()>,
+) -> io::Result<()> {
+    walk_dir(root, "", exclude, paths, visit)
+}
+
+fn walk_dir(
+    root: &Path,
+    relative: &str,
+    exclude: &[String],
+    paths: &[String],
+    visit: &mut dyn FnMut(String, &Path, &fs::Metadata) -> io::Result<()>,
+) -> io::Result<()> {
+    for item in fs::read_dir(root.join(relative))? {
+        let item = item?;
+        let name = item.file_name().to_string_lossy().to_string();
+        let path = if relative.is_empty() {
+            name
+        } else {
+            format!("{}/{}", relative, name)
+        };
+
+        // Files might vanish while a build is running.
+        let metadata = match fs::symlink_metadata(item.path()) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
+        };
+        let is_dir = metadata.is_dir();
+
+        if sync::is_local_state(&path)
+            || ignore::excluded(exclude, &path, is_dir)
+            || !within(paths, &path, is_dir)
+        {
+            continue;
+        }
+
+        if is_dir {
+            walk_dir(root, &path, exclude, paths, visit)?;
+        } else if metadata.file_type().is_symlink() || metadata.is_file() {
+            visit(path, &item.path(), &metadata)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the path is one of `paths`, inside one or a directory leading to one, any path without `paths`.
+pub fn within(paths: &[String], path: &str, is_dir: bool) -> bool {
+    paths.is_empty()
+        || paths.iter().any(|allowed| {
+            let allowed = allowed.trim_matches('/');
+            path == allowed
+                || path.starts_with(&format!("{}/", allowed))
+                || (is_dir && allowed.starts_with(&format!("{}/", path)))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(files: &[(&str, &str)]) -> Snapshot {
+        Snapshot {
+            remote_dir: String::from("~/mainframer/project"),
+            ignore: vec![String::from("build")],
+            files: files
+                .iter()
+                .map(|(path, hash)| (path.to_string(), hash.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn changed_files_added_changed_and_deleted() {
+        let acknowledged = snapshot(&[("a.rs", "1"), ("b.rs", "2"), ("c.rs", "3")]);
+        let current = snapshot(&[("a.rs", "1"), ("b.rs", "4"), ("d.rs", "5")]);
+
+        assert_eq!(
+            changed_files(&acknowledged, &current),
+            Some(vec![
+                String::from("b.rs"),
+                String::from("d.rs"),
+                String::from("c.rs")
+            ])
+        );
+    }
+
+    #[test]
+    fn changed_files_unknown_after_ignore_change() {
+        let acknowledged = snapshot(&[("a.rs", "1")]);
+        let current = Snapshot {
+            ignore: Vec::new(),
+            ..acknowledged.clone()
+        };
+
+        assert_eq!(changed_files(&acknowledged, &current), None);
+    }
+
+    #[test]
+    fn blocks_hashed_again_only_after_change() {
+        let root =
+            std::env::temp_dir().join(format!("mainframer-blocks-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.rs"), "1").unwrap();
+
+        let mut hashed = 0;
+        let hashes = |hashed: &mut u32| {
+            let metadata = fs::symlink_metadata(root.join("a.rs")).unwrap();
+            let mut blocks = Blocks::load(&root);
+            let hashes = blocks
+                .hashes(String::from("a.rs"), &metadata, || {
+                    *hashed += 1;
+                    Ok(vec![format!("hash-{}", hashed)])
+                })
+                .unwrap();
+            blocks.save(&root);
+            hashes
+        };
+
+        assert_eq!(hashes(&mut hashed), vec![String::from("hash-1")]);
+        assert_eq!(hashes(&mut hashed), vec![String::from("hash-1")]);
+
+        fs::write(root.join("a.rs"), "22").unwrap();
+        assert_eq!(hashes(&mut hashed), vec![String::from("hash-2")]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn acknowledge_from_several_threads() {
+        let root =
+            std::env::temp_dir().join(format!("mainframer-manifest-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let hosts: Vec<String> = (0..8).map(|index| format!("build-{}", index)).collect();
+
+        std::thread::scope(|scope| {
+            for host in &hosts {
+                let root = &root;
+                scope.spawn(move || acknowledge(root, host, snapshot(&[("a.rs", "1")])).unwrap());
+            }
+        });
+
+        let acknowledged: Vec<String> = load(&root).acknowledged.into_keys().collect();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(acknowledged, hosts);
+    }
+
+    #[test]
+    fn within_paths() {
+        let paths = vec![String::from("build/reports/")];
+        assert!(within(&paths, "build", true));
+        assert!(within(&paths, "build/reports/index.html", false));
+        assert!(!within(&paths, "build/classes", true));
+        assert!(!within(&paths, "src", true));
+        assert!(within(&[], "src", true));
+    }
+}
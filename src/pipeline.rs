@@ -0,0 +1,750 @@
+use std::path::Path;
+use std::time::Duration;
+
+use bus::Bus;
+
+use crate::agent;
+use crate::config::{Config, Step, StepAction};
+use crate::events::{self, Event};
+use crate::history::Record;
+use crate::hooks;
+use crate::ignore::Ignore;
+use crate::process::{self, Deadline};
+use crate::remote_command::{self, RemoteCommandErr, RemoteCommandOk};
+use crate::ssh;
+use crate::summary::{self, format_bytes};
+use crate::sync::{self, Change, PullErr, PullMode, PullOk, PullTarget};
+use crate::time::format_duration;
+use crate::trace;
+
+/// Settings shared by all steps of a run on a single remote machine.
+pub struct Run<'a> {
+    pub local_dir_absolute_path: &'a Path,
+    pub config: &'a Config,
+    pub ignore: &'a Option<Ignore>,
+    /// Variables of the run, like the shard index.
+    pub env: Vec<(String, String)>,
+    /// Whether the remote command reads the local standard input, shards running at once can't share it.
+    pub stdin: bool,
+    pub pull_target: PullTarget,
+    pub deadline: Deadline,
+    /// Lists changes of pushes and pulls instead of making them, commands and hooks don't run.
+    pub dry_run: bool,
+    pub verbose: u8,
+}
+
+/// Phases to run, all by default.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Phases {
+    pub push: bool,
+    pub exec: bool,
+    pub pull: bool,
+}
+
+/// How a step has ended.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum Outcome {
+    Done,
+    Failed,
+    /// Hook failure or timeout, no further steps run regardless of `continueOnError`.
+    Stopped,
+}
+
+/// Name of the run in the history: the executed commands.
+pub fn label(steps: &[Step]) -> String {
+    steps
+        .iter()
+        .filter_map(|step| match &step.action {
+            StepAction::Exec(command) => Some(command.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Removes steps of the skipped phases.
+pub fn select(steps: Vec<Step>, phases: Phases) -> Vec<Step> {
+    steps
+        .into_iter()
+        .filter(|step| match step.action {
+            StepAction::Push => phases.push,
+            StepAction::Exec(_) => phases.exec,
+            StepAction::Pull(_) => phases.pull,
+        })
+        .collect()
+}
+
+/// Executes the steps in order, a failed step skips the following ones unless it continues on error.
+///
+/// A pull right after an exec runs even if the exec fails so results and logs come back,
+/// in the parallel pull mode it runs during the exec.
+pub fn execute(run: &Run, steps: &[Step]) -> Record {
+    let config = run.config;
+    let mut record = Record::new(&label(steps), &config.remote.host);
+
+    // The agent replaces connections of rsync and ssh, dry runs still go through rsync.
+    let use_agent = config.remote.agent.is_some() && !run.dry_run;
+
+    let agent = match use_agent {
+        false => None,
+        true => match tracing::info_span!(target: trace::TARGET, "connect")
+            .in_scope(|| agent::Session::connect(config))
+        {
+            Err(error) => {
+                tracing::error!("{}", error);
+                record.success = false;
+                return record;
+            }
+            Ok(session) => Some(session),
+        },
+    };
+
+    let _connections = match use_agent {
+        true => None,
+        false => tracing::info_span!(target: trace::TARGET, "connect")
+            .in_scope(|| ssh::Connections::open(config)),
+    };
+
+    let project_dir_on_remote_machine =
+        sync::project_dir_on_remote_machine(config, run.local_dir_absolute_path);
+    let hooks_context = hooks::Context {
+        local_dir_absolute_path: run.local_dir_absolute_path,
+        config,
+        project_dir_on_remote_machine: &project_dir_on_remote_machine,
+        env: &run.env,
+    };
+
+    let mut executor = Executor {
+        run,
+        hooks_context,
+        project_dir_on_remote_machine: &project_dir_on_remote_machine,
+        record: &mut record,
+        summary_rows: Vec::new(),
+        agent,
+    };
+
+    let mut success = true;
+    let mut index = 0;
+
+    while index < steps.len() {
+        let step = &steps[index];
+
+        let outcomes = match &step.action {
+            StepAction::Push => vec![(executor.push(), step)],
+            StepAction::Exec(command) => match steps.get(index + 1) {
+                Some(next) if matches!(next.action, StepAction::Pull(_)) => {
+                    index += 1;
+
+                    let paths = match &next.action {
+                        StepAction::Pull(paths) => paths,
+                        _ => unreachable!(),
+                    };
+
+                    match executor.exec(command, Some(pull_config(config, paths))) {
+                        (exec, None) => vec![(exec, step)],
+                        (exec, Some(pull)) => vec![(exec, step), (pull, next)],
+                    }
+                }
+                _ => vec![(executor.exec(command, None).0, step)],
+            },
+            StepAction::Pull(paths) => vec![(executor.pull(&pull_config(config, paths)), step)],
+        };
+
+        let mut stop = false;
+
+        for (outcome, step) in outcomes {
+            match outcome {
+                Outcome::Done => {}
+                Outcome::Failed if step.continue_on_error => {
+                    tracing::warn!("Step failed, continuing on error.")
+                }
+                Outcome::Failed | Outcome::Stopped => {
+                    success = false;
+                    stop = true;
+                }
+            }
+        }
+
+        if stop {
+            break;
+        }
+
+        index += 1;
+    }
+
+    if !executor.summary_rows.is_empty() {
+        for line in summary::table(&executor.summary_rows) {
+            tracing::info!("{}", line);
+        }
+    }
+
+    record.success = success;
+    record
+}
+
+/// Config of a pull step, its own paths override `pull.paths`.
+fn pull_config(config: &Config, paths: &Option<Vec<String>>) -> Config {
+    let mut config = config.clone();
+
+    if let Some(paths) = paths {
+        config.pull.paths = paths.clone();
+    }
+
+    config
+}
+
+/// Logs changes of a dry run, deletions stand out as warnings.
+fn preview(direction: &str, changes: Result<Vec<Change>, String>) -> Outcome {
+    let changes = match changes {
+        Err(message) => {
+            tracing::error!("{} dry run failed: {}", direction, message);
+            return Outcome::Failed;
+        }
+        Ok(changes) => changes,
+    };
+
+    let (files, bytes) = changes
+        .iter()
+        .fold((0, 0), |(files, bytes), change| match change {
+            Change::Create(_, size) | Change::Update(_, size) => (files + 1, bytes + size),
+            Change::Delete(_) => (files, bytes),
+        });
+    let deletions = changes.len() - files;
+
+    tracing::info!(
+        "{} would transfer {} files ({}) and delete {}:",
+        direction,
+        files,
+        format_bytes(bytes),
+        deletions
+    );
+
+    for change in &changes {
+        match change {
+            Change::Create(path, size) => {
+                tracing::info!("  new     {} ({})", path, format_bytes(*size))
+            }
+            Change::Update(path, size) => {
+                tracing::info!("  update  {} ({})", path, format_bytes(*size))
+            }
+            Change::Delete(path) => tracing::warn!("  delete  {}", path),
+        }
+    }
+
+    Outcome::Done
+}
+
+fn add_ms(total: &mut Option<u64>, ms: u64) {
+    *total = Some(total.unwrap_or_default() + ms);
+}
+
+struct Executor<'a> {
+    run: &'a Run<'a>,
+    hooks_context: hooks::Context<'a>,
+    project_dir_on_remote_machine: &'a str,
+    record: &'a mut Record,
+    summary_rows: Vec<summary::Row>,
+    agent: Option<agent::Session>,
+}
+
+impl Executor<'_> {
+    fn hooks(&self, name: &str, hooks: &[crate::config::Hook], success: bool) -> Result<(), ()> {
+        if self.run.dry_run {
+            return Ok(());
+        }
+
+        hooks::run(name, hooks, &self.hooks_context, self.record, success)
+            .map_err(|error| tracing::error!("{}", error))
+    }
+
+    fn push(&mut self) -> Outcome {
+        let run = self.run;
+
+        if run.dry_run {
+            return preview(
+                "Push",
+                sync::preview_push(
+                    run.local_dir_absolute_path,
+                    run.config,
+                    run.ignore,
+                    run.deadline,
+                    run.verbose,
+                ),
+            );
+        }
+
+        let config = run.config;
+        let host = config.remote.host.as_str();
+
+        if self.hooks("prePush", &config.hooks.pre_push, true).is_err() {
+            return Outcome::Stopped;
+        }
+
+        tracing::info!("Pushing...");
+        events::emit(&Event::PushStarted { host });
+
+        let push_result =
+            tracing::info_span!(target: trace::TARGET, "push").in_scope(|| match &mut self.agent {
+                Some(session) => session.push(
+                    run.local_dir_absolute_path,
+                    config,
+                    run.ignore,
+                    run.deadline,
+                ),
+                None => sync::push(
+                    run.local_dir_absolute_path,
+                    config,
+                    run.ignore,
+                    run.deadline,
+                    run.verbose,
+                ),
+            });
+
+        match push_result {
+            Err(ref err) => {
+                tracing::error!(
+                    "Push failed: {}, took {}",
+                    err.message,
+                    format_duration(err.duration)
+                );
+                events::emit(&Event::PushFinished {
+                    host,
+                    success: false,
+                    duration_ms: events::millis(err.duration),
+                    stats: None,
+                    message: Some(&err.message),
+                });
+                add_ms(&mut self.record.push_ms, events::millis(err.duration));
+                self.record.timed_out |= err.timed_out;
+            }
+            Ok(ref ok) => {
+                tracing::info!("Push done: took {}.", format_duration(ok.duration));
+                events::emit(&Event::PushFinished {
+                    host,
+                    success: true,
+                    duration_ms: events::millis(ok.duration),
+                    stats: Some(&ok.stats),
+                    message: None,
+                });
+                add_ms(&mut self.record.push_ms, events::millis(ok.duration));
+                self.record.bytes_sent += ok.stats.bytes_sent;
+                self.record.bytes_received += ok.stats.bytes_received;
+                self.summary_rows.push(summary::Row::new(
+                    "push",
+                    ok.duration,
+                    Some(ok.stats.clone()),
+                ));
+            }
+        }
+
+        if self
+            .hooks("postPush", &config.hooks.post_push, push_result.is_ok())
+            .is_err()
+        {
+            return Outcome::Stopped;
+        }
+
+        match push_result {
+            Err(ref err) if err.timed_out => Outcome::Stopped,
+            Err(_) => Outcome::Failed,
+            Ok(_) => Outcome::Done,
+        }
+    }
+
+    /// Executes the command and pulls with `pull_config` if set, returns outcomes of both.
+    fn exec(&mut self, command: &str, pull_config: Option<Config>) -> (Outcome, Option<Outcome>) {
+        let run = self.run;
+
+        if run.dry_run {
+            tracing::info!("Dry run, not executing: {}", command);
+            return (
+                Outcome::Done,
+                pull_config.map(|pull_config| self.pull(&pull_config)),
+            );
+        }
+
+        let config = run.config;
+        let host = config.remote.host.as_str();
+
+        if self.hooks("preExec", &config.hooks.pre_exec, true).is_err() {
+            return (Outcome::Stopped, None);
+        }
+
+        // Pull waits for this signal, it is sent once the command and hooks after it are done.
+        let mut remote_command_finished: Bus<Result<RemoteCommandOk, RemoteCommandErr>> =
+            Bus::new(1);
+
+        let start_pull = |pull_config: &Config, signal| {
+            sync::pull(
+                run.local_dir_absolute_path,
+                pull_config.clone(),
+                run.ignore.clone(),
+                run.pull_target.clone(),
+                &pull_config.pull.mode,
+                signal,
+                run.deadline,
+                run.verbose,
+            )
+        };
+
+        // The agent pulls once the command is done.
+        let parallel = self.agent.is_none()
+            && matches!(pull_config, Some(ref pull_config) if pull_config.pull.mode == PullMode::Parallel);
+
+        // Parallel pulls start together with the command.
+        let mut pull_finished_rx = None;
+
+        if parallel {
+            if self.hooks("prePull", &config.hooks.pre_pull, true).is_err() {
+                return (Outcome::Stopped, None);
+            }
+
+            pull_finished_rx = pull_config
+                .as_ref()
+                .map(|pull_config| start_pull(pull_config, remote_command_finished.add_rx()));
+
+            tracing::info!("Executing command on remote machine (pulling in parallel)...")
+        } else {
+            tracing::info!("Executing command on remote machine...")
+        }
+
+        let timeout = run
+            .deadline
+            .timeout(config.remote.timeout.map(Duration::from_secs));
+
+        // Standard input would compete with what kills the command on timeout, the agent has no stream for it.
+        if run.stdin && (self.agent.is_some() || timeout.is_some()) && process::stdin_is_terminal()
+        {
+            tracing::warn!(
+                "The remote command doesn't read standard input with a timeout or 'remote.agent'."
+            );
+        }
+
+        let remote_command_result = match &mut self.agent {
+            Some(session) => remote_command::execute_with_agent(
+                session,
+                command,
+                config,
+                run.ignore,
+                run.local_dir_absolute_path,
+                self.project_dir_on_remote_machine,
+                run.env.clone(),
+                run.deadline,
+            ),
+            None => remote_command::execute_remote_command(
+                command.to_string(),
+                config.clone(),
+                run.local_dir_absolute_path,
+                self.project_dir_on_remote_machine.to_string(),
+                run.env.clone(),
+                run.deadline,
+                run.stdin,
+                1,
+            )
+            .pop()
+            .unwrap()
+            .recv()
+            .unwrap(),
+        };
+
+        let exec_duration = match remote_command_result {
+            Err(ref err) => err.duration,
+            Ok(ref ok) => ok.duration,
+        };
+
+        add_ms(&mut self.record.exec_ms, events::millis(exec_duration));
+        self.record.exit_code = match remote_command_result {
+            Err(ref err) => err.exit_code,
+            Ok(_) => Some(0),
+        };
+        self.summary_rows
+            .push(summary::Row::new("exec", exec_duration, None));
+
+        match remote_command_result {
+            Err(ref err) => {
+                if err.timed_out {
+                    tracing::error!(
+                        "Execution timed out: took {}.",
+                        format_duration(err.duration)
+                    );
+                } else {
+                    tracing::error!("Execution failed: took {}.", format_duration(err.duration));
+                }
+                events::emit(&Event::ExecFinished {
+                    host,
+                    success: false,
+                    exit_code: err.exit_code,
+                    duration_ms: events::millis(err.duration),
+                    timed_out: err.timed_out,
+                });
+                self.record.timed_out |= err.timed_out;
+            }
+            Ok(ref ok) => {
+                tracing::info!("Execution done: took {}.", format_duration(ok.duration));
+                events::emit(&Event::ExecFinished {
+                    host,
+                    success: true,
+                    exit_code: Some(0),
+                    duration_ms: events::millis(ok.duration),
+                    timed_out: false,
+                });
+            }
+        }
+
+        let exec_outcome = match remote_command_result {
+            Err(ref err) if err.timed_out => Outcome::Stopped,
+            Err(_) => Outcome::Failed,
+            Ok(_) => Outcome::Done,
+        };
+
+        // Dropping the signal without sending stops parallel pulls.
+        if self
+            .hooks(
+                "postExec",
+                &config.hooks.post_exec,
+                remote_command_result.is_ok(),
+            )
+            .is_err()
+        {
+            return (Outcome::Stopped, None);
+        }
+
+        let pull_config = match pull_config {
+            None => return (exec_outcome, None),
+            Some(pull_config) => pull_config,
+        };
+
+        let pull_finished_rx = match pull_finished_rx {
+            Some(pull_finished_rx) => pull_finished_rx,
+            None => {
+                if self
+                    .hooks(
+                        "prePull",
+                        &config.hooks.pre_pull,
+                        remote_command_result.is_ok(),
+                    )
+                    .is_err()
+                {
+                    return (Outcome::Stopped, None);
+                }
+
+                if let Some(session) = &mut self.agent {
+                    tracing::info!("Pulling...");
+
+                    let pull_result =
+                        tracing::info_span!(target: trace::TARGET, "pull").in_scope(|| {
+                            session.pull(
+                                run.local_dir_absolute_path,
+                                &pull_config,
+                                run.ignore,
+                                &run.pull_target,
+                                run.deadline,
+                            )
+                        });
+
+                    let pull_outcome = self.finish_pull(pull_result, remote_command_result.is_ok());
+
+                    return (exec_outcome, Some(pull_outcome));
+                }
+
+                start_pull(&pull_config, remote_command_finished.add_rx())
+            }
+        };
+
+        tracing::info!("Pulling...");
+        remote_command_finished.broadcast(remote_command_result.clone());
+
+        let pull_result = pull_finished_rx
+            .recv()
+            .expect("Could not receive remote_to_local_sync_result");
+
+        let pull_outcome = self.finish_pull(pull_result, remote_command_result.is_ok());
+
+        (exec_outcome, Some(pull_outcome))
+    }
+
+    /// Pulls on its own, without a remote command before.
+    fn pull(&mut self, pull_config: &Config) -> Outcome {
+        let run = self.run;
+
+        if run.dry_run {
+            return preview(
+                "Pull",
+                sync::preview_pull(
+                    run.local_dir_absolute_path,
+                    pull_config,
+                    run.ignore,
+                    &run.pull_target,
+                    run.deadline,
+                    run.verbose,
+                ),
+            );
+        }
+
+        if self
+            .hooks("prePull", &run.config.hooks.pre_pull, true)
+            .is_err()
+        {
+            return Outcome::Stopped;
+        }
+
+        tracing::info!("Pulling...");
+
+        let pull_result = tracing::info_span!(target: trace::TARGET, "pull").in_scope(|| {
+            if let Some(session) = &mut self.agent {
+                return session.pull(
+                    run.local_dir_absolute_path,
+                    pull_config,
+                    run.ignore,
+                    &run.pull_target,
+                    run.deadline,
+                );
+            }
+
+            sync::pull_once(
+                run.local_dir_absolute_path,
+                pull_config,
+                run.ignore,
+                &run.pull_target,
+                run.deadline,
+                run.verbose,
+            )
+        });
+
+        self.finish_pull(pull_result, true)
+    }
+
+    /// Records the pull result and runs `postPull` hooks, `success` is the status before the pull.
+    fn finish_pull(&mut self, pull_result: Result<PullOk, PullErr>, success: bool) -> Outcome {
+        let config = self.run.config;
+
+        match pull_result {
+            Err(ref err) => tracing::error!(
+                "Pull failed: {}, took {}.",
+                err.message,
+                format_duration(err.duration)
+            ),
+            Ok(ref ok) => tracing::info!("Pull done: took {}", format_duration(ok.duration)),
+        }
+
+        let pull_duration = match pull_result {
+            Err(ref err) => err.duration,
+            Ok(ref ok) => ok.duration,
+        };
+
+        events::emit(&Event::PullFinished {
+            host: &config.remote.host,
+            success: pull_result.is_ok(),
+            duration_ms: events::millis(pull_duration),
+        });
+
+        add_ms(&mut self.record.pull_ms, events::millis(pull_duration));
+
+        if let Ok(ref ok) = pull_result {
+            for (index, transfer) in ok.transfers.iter().enumerate() {
+                self.record.bytes_sent += transfer.stats.bytes_sent;
+                self.record.bytes_received += transfer.stats.bytes_received;
+
+                let phase = match ok.transfers.len() {
+                    1 => String::from("pull"),
+                    _ => format!("pull #{}", index + 1),
+                };
+                self.summary_rows.push(summary::Row::new(
+                    &phase,
+                    transfer.duration,
+                    Some(transfer.stats.clone()),
+                ));
+            }
+
+            if ok.transfers.len() > 1 {
+                self.summary_rows
+                    .push(summary::Row::new("pull (perceived)", ok.duration, None));
+            }
+        }
+
+        self.record.timed_out |= matches!(pull_result, Err(ref err) if err.timed_out);
+
+        if self
+            .hooks(
+                "postPull",
+                &config.hooks.post_pull,
+                success && pull_result.is_ok(),
+            )
+            .is_err()
+        {
+            return Outcome::Stopped;
+        }
+
+        match pull_result {
+            Err(ref err) if err.timed_out => Outcome::Stopped,
+            Err(_) => Outcome::Failed,
+            Ok(_) => Outcome::Done,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(actions: Vec<StepAction>) -> Vec<Step> {
+        actions
+            .into_iter()
+            .map(|action| Step {
+                action,
+                continue_on_error: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn select_without_push() {
+        let phases = Phases {
+            push: false,
+            exec: true,
+            pull: true,
+        };
+
+        assert_eq!(
+            select(Step::default_steps(String::from("make")), phases),
+            steps(vec![
+                StepAction::Exec(String::from("make")),
+                StepAction::Pull(None)
+            ])
+        );
+    }
+
+    #[test]
+    fn select_pull_only() {
+        let phases = Phases {
+            push: false,
+            exec: false,
+            pull: true,
+        };
+
+        assert_eq!(
+            select(Step::default_steps(String::new()), phases),
+            steps(vec![StepAction::Pull(None)])
+        );
+    }
+
+    #[test]
+    fn label_of_default_steps() {
+        assert_eq!(
+            label(&Step::default_steps(String::from("./gradlew build"))),
+            "./gradlew build"
+        );
+    }
+
+    #[test]
+    fn label_of_several_execs() {
+        let steps = steps(vec![
+            StepAction::Push,
+            StepAction::Exec(String::from("make")),
+            StepAction::Pull(Some(vec![String::from("out")])),
+            StepAction::Exec(String::from("make test")),
+        ]);
+
+        assert_eq!(label(&steps), "make; make test");
+    }
+}
@@ -0,0 +1,406 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::unbounded;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::process;
+use crate::ssh;
+
+/// Reports number of CPUs on the first line and `uptime` on the second one.
+const PROBE_COMMAND: &str =
+    "nproc 2>/dev/null || getconf _NPROCESSORS_ONLN 2>/dev/null || sysctl -n hw.ncpu; uptime";
+
+/// Load per CPU at which a sticky host gets replaced by a less loaded one.
+const STICKY_LOAD_THRESHOLD: f64 = 1.0;
+
+#[derive(Debug, Default, Eq, PartialEq, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HostSelection {
+    /// First reachable host in the configuration order.
+    #[default]
+    FirstAvailable,
+
+    /// Reachable host with the lowest load average per CPU.
+    LeastLoad,
+
+    /// Next reachable host after the one used previously.
+    RoundRobin,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Probe {
+    pub host: String,
+    /// Load average for the last minute divided by number of CPUs.
+    pub load: Option<f64>,
+}
+
+/// Picks a remote host for the run, probes the candidates in parallel.
+pub fn select_host(config: &Config, local_dir_absolute_path: &Path) -> Result<String, String> {
+    select_hosts(config, local_dir_absolute_path, 1).map(|mut hosts| hosts.remove(0))
+}
+
+/// Picks `count` distinct remote hosts, the first one is remembered for the next selection.
+pub fn select_hosts(
+    config: &Config,
+    local_dir_absolute_path: &Path,
+    count: usize,
+) -> Result<Vec<String>, String> {
+    let candidates = config.remote.candidates();
+
+    if candidates.len() < count {
+        return Err(format!(
+            "{} remote hosts are required, but only {} configured",
+            count,
+            candidates.len()
+        ));
+    }
+
+    let timeout = Duration::from_secs(config.remote.probe_timeout);
+    let mut probes = probe_all(config, &candidates, timeout);
+
+    for candidate in &candidates {
+        match probes.iter().find(|probe| &probe.host == candidate) {
+            // A single one fails the selection with the same message.
+            None if candidates.len() == 1 => {}
+            None => tracing::warn!("Remote host {} is unreachable.", candidate),
+            Some(probe) => match probe.load {
+                None => tracing::debug!("Remote host {} is reachable, load unknown.", candidate),
+                Some(load) => tracing::debug!(
+                    "Remote host {} is reachable, load {:.2} per CPU.",
+                    candidate,
+                    load
+                ),
+            },
+        }
+    }
+
+    let state_file = state_file(local_dir_absolute_path);
+    let mut previous = fs::read_to_string(&state_file)
+        .ok()
+        .map(|contents| contents.trim().to_string());
+
+    let mut hosts: Vec<String> = Vec::with_capacity(count);
+
+    while hosts.len() < count {
+        match choose(
+            &candidates,
+            &probes,
+            &config.remote.selection,
+            previous.as_deref(),
+        ) {
+            None => break,
+            Some(host) => {
+                probes.retain(|probe| probe.host != host);
+                previous = Some(host.clone());
+                hosts.push(host);
+            }
+        }
+    }
+
+    if let ([host], []) = (candidates.as_slice(), hosts.as_slice()) {
+        return Err(format!("Remote host {} is unreachable", host));
+    }
+
+    if hosts.is_empty() {
+        return Err(format!(
+            "None of the remote hosts are reachable: {}",
+            candidates.join(", ")
+        ));
+    }
+
+    if hosts.len() < count {
+        return Err(format!(
+            "{} remote hosts are required, but only {} reachable: {}",
+            count,
+            hosts.len(),
+            hosts.join(", ")
+        ));
+    }
+
+    if let Some(dir) = state_file.parent() {
+        // Failing to remember the host only affects the next selection.
+        let _ = fs::create_dir_all(dir).and_then(|_| fs::write(&state_file, &hosts[0]));
+    }
+
+    match hosts.len() {
+        1 => tracing::info!("Selected remote host {}.", hosts[0]),
+        _ => tracing::info!("Selected remote hosts {}.", hosts.join(", ")),
+    }
+
+    Ok(hosts)
+}
+
+/// Host remembered by the previous selection if it is still configured.
+pub fn previous_host(config: &Config, local_dir_absolute_path: &Path) -> Option<String> {
+    fs::read_to_string(state_file(local_dir_absolute_path))
+        .ok()
+        .map(|contents| contents.trim().to_string())
+        .filter(|host| config.remote.candidates().contains(host))
+}
+
+fn state_file(local_dir_absolute_path: &Path) -> PathBuf {
+    local_dir_absolute_path
+        .join(".mainframer")
+        .join("cache")
+        .join("host")
+}
+
+fn probe_all(config: &Config, candidates: &[String], timeout: Duration) -> Vec<Probe> {
+    let (probe_tx, probe_rx) = unbounded();
+
+    for candidate in candidates {
+        let probe_tx = probe_tx.clone();
+        let config = config.clone();
+        let host = candidate.clone();
+
+        thread::spawn(move || {
+            probe_tx
+                .send(probe(&config, &host, timeout))
+                .expect("Could not send probe result");
+        });
+    }
+
+    drop(probe_tx);
+
+    probe_rx.iter().flatten().collect()
+}
+
+fn probe(config: &Config, host: &str, timeout: Duration) -> Option<Probe> {
+    let destination = ssh::destination(host, config.remote.user.as_ref());
+    let mut command = ssh::command(&config.remote, &destination);
+
+    command
+        .arg("-o")
+        .arg("BatchMode=yes")
+        .arg("-o")
+        .arg(format!("ConnectTimeout={}", timeout.as_secs().max(1)))
+        .arg(&destination)
+        .arg(PROBE_COMMAND)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+
+    tracing::debug!("Probing remote host: {:?}", command);
+
+    let mut child = process::spawn(&mut command, Some(timeout)).ok()?;
+    let mut stdout = child.stdout.take()?;
+
+    // Read output in background so a chatty host can't block on a full pipe.
+    let output = thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stdout.read_to_string(&mut output);
+        output
+    });
+
+    match process::wait_with_timeout(&mut child, timeout) {
+        Ok(Some(status)) if status.success() => Some(Probe {
+            host: host.to_string(),
+            load: parse_load(&output.join().unwrap_or_default()),
+        }),
+        _ => None,
+    }
+}
+
+/// Parses output of the probe command into load average per CPU.
+fn parse_load(output: &str) -> Option<f64> {
+    let mut lines = output.lines();
+    let cpus: f64 = lines.next()?.trim().parse().ok()?;
+    let uptime = lines.next()?;
+
+    // Linux: "load average: 0.52, 0.58, 0.59", macOS: "load averages: 1.52 1.58 1.59".
+    let (_, averages) = uptime.split_once("load average")?;
+    let (_, averages) = averages.split_once(':')?;
+    let load: f64 = averages
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .find(|value| !value.is_empty())?
+        .parse()
+        .ok()?;
+
+    if cpus > 0.0 {
+        Some(load / cpus)
+    } else {
+        None
+    }
+}
+
+fn choose(
+    candidates: &[String],
+    probes: &[Probe],
+    selection: &HostSelection,
+    previous: Option<&str>,
+) -> Option<String> {
+    // Keep the order from the configuration, probes arrive in completion order.
+    let reachable: Vec<&Probe> = candidates
+        .iter()
+        .filter_map(|candidate| probes.iter().find(|probe| &probe.host == candidate))
+        .collect();
+
+    let sticky =
+        previous.and_then(|previous| reachable.iter().find(|probe| probe.host == previous));
+
+    let chosen = match selection {
+        HostSelection::FirstAvailable => sticky.or_else(|| reachable.first()),
+        HostSelection::LeastLoad => sticky
+            .filter(|probe| !matches!(probe.load, Some(load) if load >= STICKY_LOAD_THRESHOLD))
+            .or_else(|| {
+                reachable.iter().min_by(|a, b| {
+                    let a = a.load.unwrap_or(f64::MAX);
+                    let b = b.load.unwrap_or(f64::MAX);
+                    a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+                })
+            }),
+        HostSelection::RoundRobin => {
+            let start = previous
+                .and_then(|previous| candidates.iter().position(|host| host == previous))
+                .map_or(0, |position| position + 1);
+
+            candidates
+                .iter()
+                .cycle()
+                .skip(start)
+                .take(candidates.len())
+                .find_map(|candidate| reachable.iter().find(|probe| &probe.host == candidate))
+        }
+    };
+
+    chosen.map(|probe| probe.host.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates() -> Vec<String> {
+        vec![
+            String::from("computer1"),
+            String::from("computer2"),
+            String::from("computer3"),
+        ]
+    }
+
+    fn probe(host: &str, load: f64) -> Probe {
+        Probe {
+            host: String::from(host),
+            load: Some(load),
+        }
+    }
+
+    #[test]
+    fn parse_load_linux() {
+        let output = "4\n 10:00:00 up 1 day,  2:03,  1 user,  load average: 2.00, 1.50, 1.00\n";
+        assert_eq!(parse_load(output), Some(0.5));
+    }
+
+    #[test]
+    fn parse_load_macos() {
+        let output = "8\n10:00  up 1 day,  2:03, 1 user, load averages: 4.00 3.50 3.00\n";
+        assert_eq!(parse_load(output), Some(0.5));
+    }
+
+    #[test]
+    fn parse_load_garbage() {
+        assert_eq!(parse_load("yooo"), None);
+    }
+
+    #[test]
+    fn choose_first_available_skips_unreachable() {
+        let probes = vec![probe("computer3", 0.1), probe("computer2", 0.9)];
+        assert_eq!(
+            choose(&candidates(), &probes, &HostSelection::FirstAvailable, None),
+            Some(String::from("computer2"))
+        );
+    }
+
+    #[test]
+    fn choose_first_available_sticky() {
+        let probes = vec![probe("computer1", 0.1), probe("computer3", 0.1)];
+        assert_eq!(
+            choose(
+                &candidates(),
+                &probes,
+                &HostSelection::FirstAvailable,
+                Some("computer3")
+            ),
+            Some(String::from("computer3"))
+        );
+    }
+
+    #[test]
+    fn choose_least_load() {
+        let probes = vec![
+            probe("computer1", 0.9),
+            probe("computer2", 0.2),
+            probe("computer3", 0.5),
+        ];
+        assert_eq!(
+            choose(&candidates(), &probes, &HostSelection::LeastLoad, None),
+            Some(String::from("computer2"))
+        );
+    }
+
+    #[test]
+    fn choose_least_load_sticky_unless_overloaded() {
+        let probes = vec![probe("computer1", 0.9), probe("computer2", 0.2)];
+        assert_eq!(
+            choose(
+                &candidates(),
+                &probes,
+                &HostSelection::LeastLoad,
+                Some("computer1")
+            ),
+            Some(String::from("computer1"))
+        );
+
+        let probes = vec![probe("computer1", 1.5), probe("computer2", 0.2)];
+        assert_eq!(
+            choose(
+                &candidates(),
+                &probes,
+                &HostSelection::LeastLoad,
+                Some("computer1")
+            ),
+            Some(String::from("computer2"))
+        );
+    }
+
+    #[test]
+    fn choose_round_robin() {
+        let probes = vec![
+            probe("computer1", 0.1),
+            probe("computer2", 0.1),
+            probe("computer3", 0.1),
+        ];
+        assert_eq!(
+            choose(
+                &candidates(),
+                &probes,
+                &HostSelection::RoundRobin,
+                Some("computer3")
+            ),
+            Some(String::from("computer1"))
+        );
+        assert_eq!(
+            choose(
+                &candidates(),
+                &probes,
+                &HostSelection::RoundRobin,
+                Some("computer1")
+            ),
+            Some(String::from("computer2"))
+        );
+    }
+
+    #[test]
+    fn choose_none_reachable() {
+        assert_eq!(
+            choose(&candidates(), &[], &HostSelection::FirstAvailable, None),
+            None
+        );
+    }
+}
@@ -0,0 +1,340 @@
+use std::fmt::Display;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::time::format_duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Exit code of Mainframer when the run or one of its phases has timed out, same as of `timeout`.
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_GROUP: AtomicI32 = AtomicI32::new(0);
+
+/// Process groups of running commands spawned with a timeout, see [`spawn`].
+static GROUPS: [AtomicI32; 64] = [NO_GROUP; 64];
+
+static FORWARD_SIGNALS: Once = Once::new();
+
+/// Spawns the command, with a timeout in its own process group so [`wait_with_timeout`] kills
+/// everything it has started, like `ssh` of `rsync`, instead of only the command itself.
+///
+/// Ctrl-C only reaches the foreground process group, Mainframer forwards it to the ones of its commands.
+pub fn spawn(command: &mut Command, timeout: Option<Duration>) -> io::Result<Child> {
+    if timeout.is_none() {
+        return command.spawn();
+    }
+
+    FORWARD_SIGNALS.call_once(forward_signals);
+
+    // Only calls async-signal-safe `setpgid` between fork and exec.
+    unsafe {
+        command.pre_exec(|| match libc::setpgid(0, 0) {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        });
+    }
+
+    let child = command.spawn()?;
+
+    // Without a free slot Ctrl-C doesn't reach the command, the timeout still kills it.
+    let _ = GROUPS.iter().find(|group| {
+        group
+            .compare_exchange(0, child.id() as i32, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    });
+
+    Ok(child)
+}
+
+fn forward_signals() {
+    for signal in [libc::SIGINT, libc::SIGQUIT] {
+        unsafe {
+            let previous = libc::signal(signal, forward as extern "C" fn(libc::c_int) as usize);
+
+            // Ignored ones stay ignored, like for a run started in the background.
+            if previous == libc::SIG_IGN {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+        }
+    }
+}
+
+extern "C" fn forward(signal: libc::c_int) {
+    for group in &GROUPS {
+        match group.load(Ordering::SeqCst) {
+            0 => {}
+            group => unsafe {
+                libc::kill(-group, signal);
+            },
+        }
+    }
+
+    // Mainframer itself stops the way it would without the handler.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+/// Whether standard input of Mainframer is a terminal, where someone might type into the remote command.
+pub fn stdin_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 }
+}
+
+/// Waits for the child to exit, kills it along with its process group once the timeout has elapsed.
+///
+/// Returns `Ok(None)` if the child was killed because of the timeout.
+pub fn wait_with_timeout(child: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+
+    let result = loop {
+        match child.try_wait() {
+            Ok(None) => {}
+            result => break result,
+        }
+
+        if Instant::now() >= deadline {
+            // Not a group leader unless started by `spawn`, the child and the group
+            // might have exited in between, killing them is best-effort.
+            unsafe {
+                libc::kill(-(child.id() as i32), libc::SIGKILL);
+            }
+            let _ = child.kill();
+            break child.wait().map(|_| None);
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    for group in &GROUPS {
+        let _ = group.compare_exchange(child.id() as i32, 0, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    result
+}
+
+/// Waits for the child to exit, see [`wait_with_timeout`] for the result with a timeout.
+pub fn wait(child: &mut Child, timeout: Option<Duration>) -> io::Result<Option<ExitStatus>> {
+    match timeout {
+        None => child.wait().map(Some),
+        Some(timeout) => wait_with_timeout(child, timeout),
+    }
+}
+
+/// Error which might go away on its own, like a dropped connection.
+pub trait Transient {
+    fn transient(&self) -> bool;
+}
+
+/// Runs the attempt again after transient errors, waiting `backoff` doubled after each one.
+///
+/// Pauses are cut to the time left until the deadline, there are no more attempts once it has passed.
+pub fn retry<T, E: Transient + Display>(
+    what: &str,
+    retries: u32,
+    backoff: Duration,
+    deadline: Deadline,
+    mut attempt: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut delay = backoff;
+    let mut number = 1;
+
+    loop {
+        match attempt() {
+            Err(error) if error.transient() && number <= retries && !deadline.passed() => {
+                let pause = deadline.timeout(Some(delay)).unwrap_or(delay);
+
+                tracing::warn!(
+                    "{} failed with a transient error (attempt {} of {}): {}",
+                    what,
+                    number,
+                    retries + 1,
+                    error
+                );
+                tracing::warn!("Retrying in {}...", format_duration(pause));
+                thread::sleep(pause);
+
+                if deadline.passed() {
+                    return Err(error);
+                }
+
+                delay *= 2;
+                number += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Point in time the whole run has to be finished by, if any.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub fn after(timeout: Option<Duration>, start: Instant) -> Self {
+        Self(timeout.map(|timeout| start + timeout))
+    }
+
+    pub fn passed(&self) -> bool {
+        matches!(self.0, Some(deadline) if Instant::now() >= deadline)
+    }
+
+    /// Timeout of a phase: its own one, cut to the time left until the deadline.
+    pub fn timeout(&self, phase_timeout: Option<Duration>) -> Option<Duration> {
+        let left = self
+            .0
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+        match (phase_timeout, left) {
+            (None, left) => left,
+            (phase_timeout, None) => phase_timeout,
+            (Some(phase_timeout), Some(left)) => Some(phase_timeout.min(left)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::process::Stdio;
+
+    #[test]
+    fn wait_with_timeout_kills_the_process_group() {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("sleep 30 & sleep 30")
+            .stdout(Stdio::piped());
+
+        let mut child = spawn(&mut command, Some(Duration::from_millis(100))).unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let start = Instant::now();
+
+        assert_eq!(
+            wait_with_timeout(&mut child, Duration::from_millis(100)).unwrap(),
+            None
+        );
+
+        // The background `sleep` keeps the pipe open unless it got killed as well.
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(GROUPS
+            .iter()
+            .all(|group| group.load(Ordering::SeqCst) != child.id() as i32));
+    }
+
+    #[derive(Debug)]
+    struct TestErr(bool);
+
+    impl Transient for TestErr {
+        fn transient(&self) -> bool {
+            self.0
+        }
+    }
+
+    impl Display for TestErr {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "transient: {}", self.0)
+        }
+    }
+
+    #[test]
+    fn retry_transient_errors() {
+        let mut attempts = 0;
+        let result = retry("Test", 2, Duration::ZERO, Deadline::default(), || {
+            attempts += 1;
+            match attempts {
+                3 => Ok(attempts),
+                _ => Err(TestErr(true)),
+            }
+        });
+
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn retry_gives_up() {
+        let mut attempts = 0;
+        let result: Result<(), TestErr> =
+            retry("Test", 2, Duration::ZERO, Deadline::default(), || {
+                attempts += 1;
+                Err(TestErr(true))
+            });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn retry_stops_at_deadline() {
+        let deadline = Deadline::after(Some(Duration::from_millis(100)), Instant::now());
+        let start = Instant::now();
+        let mut attempts = 0;
+
+        let result: Result<(), TestErr> =
+            retry("Test", 5, Duration::from_secs(10), deadline, || {
+                attempts += 1;
+                Err(TestErr(true))
+            });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn retry_not_transient_errors() {
+        let mut attempts = 0;
+        let result: Result<(), TestErr> =
+            retry("Test", 2, Duration::ZERO, Deadline::default(), || {
+                attempts += 1;
+                Err(TestErr(false))
+            });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn deadline_none() {
+        assert_eq!(Deadline::default().timeout(None), None);
+        assert_eq!(
+            Deadline::default().timeout(Some(Duration::from_secs(5))),
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn deadline_cuts_phase_timeout() {
+        let deadline = Deadline::after(Some(Duration::from_secs(10)), Instant::now());
+
+        assert_eq!(
+            deadline.timeout(Some(Duration::from_secs(5))),
+            Some(Duration::from_secs(5))
+        );
+        assert!(
+            deadline.timeout(Some(Duration::from_secs(60))).unwrap() <= Duration::from_secs(10)
+        );
+        assert!(deadline.timeout(None).unwrap() <= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn deadline_passed() {
+        let deadline = Deadline::after(
+            Some(Duration::from_secs(1)),
+            Instant::now() - Duration::from_secs(2),
+        );
+
+        assert_eq!(deadline.timeout(None), Some(Duration::ZERO));
+    }
+}
@@ -1,13 +1,49 @@
+use std::fmt;
 use std::io;
 use std::io::Write;
-use std::process::Command;
+use std::path::Path;
 use std::process::Stdio;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use bus::{Bus, BusReader};
+use serde::{Deserialize, Serialize};
 
-use crate::config::Config;
+use crate::agent;
+use crate::config::{Config, PathMapping, Remote};
+use crate::events::{self, Event, Stream};
+use crate::ignore::Ignore;
+use crate::process::{self, Deadline, Transient};
+use crate::shell;
+use crate::ssh;
+use crate::trace;
+
+/// Replaces environment variable values in logs.
+const REDACTED: &str = "<redacted>";
+
+/// Prefix of the lines with the project directory resolved on the remote machine, they are not printed.
+const REMOTE_DIR_MARKER: &str = "__MAINFRAMER_REMOTE_DIR__:";
+
+/// Output lines of remote commands while capturing, see [`start_capture`].
+static CAPTURED: Mutex<Option<Vec<OutputLine>>> = Mutex::new(None);
+
+/// Line of remote command output as it was logged.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct OutputLine {
+    pub stream: Stream,
+    pub line: String,
+}
+
+/// Starts keeping output lines of remote commands in addition to logging them.
+pub fn start_capture() {
+    *CAPTURED.lock().unwrap() = Some(Vec::new());
+}
+
+/// Stops capturing, returns lines since [`start_capture`].
+pub fn finish_capture() -> Vec<OutputLine> {
+    CAPTURED.lock().unwrap().take().unwrap_or_default()
+}
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct RemoteCommandOk {
@@ -17,12 +53,39 @@ pub struct RemoteCommandOk {
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct RemoteCommandErr {
     pub duration: Duration,
+    /// `None` if the command was terminated by a signal or could not be awaited.
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
 }
 
+/// Exit code of `ssh` when it could not connect, might also be returned by the command itself.
+const SSH_CONNECTION_ERROR_EXIT_CODE: i32 = 255;
+
+impl Transient for RemoteCommandErr {
+    fn transient(&self) -> bool {
+        !self.timed_out && self.exit_code == Some(SSH_CONNECTION_ERROR_EXIT_CODE)
+    }
+}
+
+impl fmt::Display for RemoteCommandErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.exit_code {
+            None => write!(f, "no exit code"),
+            Some(exit_code) => write!(f, "exit code {}", exit_code),
+        }
+    }
+}
+
+/// With `stdin` the command reads the local standard input unless it has a timeout.
+#[allow(clippy::too_many_arguments)]
 pub fn execute_remote_command(
     remote_command: String,
     config: Config,
+    local_dir_absolute_path: &Path,
     project_dir_on_remote_machine: String,
+    env: Vec<(String, String)>,
+    deadline: Deadline,
+    stdin: bool,
     number_of_readers: usize,
 ) -> Vec<BusReader<Result<RemoteCommandOk, RemoteCommandErr>>> {
     let mut bus: Bus<Result<RemoteCommandOk, RemoteCommandErr>> = Bus::new(1);
@@ -33,29 +96,370 @@ pub fn execute_remote_command(
         readers.push(bus.add_rx())
     }
 
+    let rewriter = rewriter(
+        &config,
+        local_dir_absolute_path,
+        &project_dir_on_remote_machine,
+    );
+
+    let env = remote_env(&config.remote, std::env::vars(), env);
+    let span = tracing::Span::current();
+
     thread::spawn(move || {
-        bus.broadcast(_execute_remote_command(
-            &remote_command,
-            &config,
-            &project_dir_on_remote_machine,
+        let _guard = span.enter();
+        let _exec_span = tracing::info_span!(target: trace::TARGET, "exec").entered();
+
+        bus.broadcast(process::retry(
+            "Remote command",
+            config.remote.retries,
+            Duration::from_secs(config.remote.retry_backoff),
+            deadline,
+            || {
+                _execute_remote_command(
+                    &remote_command,
+                    &config,
+                    &project_dir_on_remote_machine,
+                    &env,
+                    rewriter.clone(),
+                    deadline.timeout(config.remote.timeout.map(Duration::from_secs)),
+                    stdin,
+                )
+            },
         ));
     });
 
     readers
 }
 
-struct Message;
+/// Executes the remote command through the agent, output gets logged the same way as over `ssh`.
+#[allow(clippy::too_many_arguments)]
+pub fn execute_with_agent(
+    session: &mut agent::Session,
+    remote_command: &str,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    local_dir_absolute_path: &Path,
+    project_dir_on_remote_machine: &str,
+    env: Vec<(String, String)>,
+    deadline: Deadline,
+) -> Result<RemoteCommandOk, RemoteCommandErr> {
+    let _exec_span = tracing::info_span!(target: trace::TARGET, "exec").entered();
+    let start_time = Instant::now();
+
+    let timeout = deadline.timeout(config.remote.timeout.map(Duration::from_secs));
+    let rewriter = rewriter(
+        config,
+        local_dir_absolute_path,
+        project_dir_on_remote_machine,
+    );
+    let env = remote_env(&config.remote, std::env::vars(), env);
+
+    let script = remote_script(
+        remote_command,
+        project_dir_on_remote_machine,
+        &env,
+        rewriter.is_some(),
+        timeout.is_some(),
+    );
+
+    let rewriter = rewriter.map(|rewriter| Arc::new(RwLock::new(rewriter)));
+    let mut message = Message {
+        host: config.remote.host.clone(),
+        stream: Stream::Stdout,
+        rewriter: rewriter.clone(),
+        pending: Vec::new(),
+    };
+    let mut err_message = Message {
+        host: config.remote.host.clone(),
+        stream: Stream::Stderr,
+        rewriter,
+        pending: Vec::new(),
+    };
+
+    // Only consumers of events have a use for changed files, watching a large project costs the remote machine.
+    let watch = if events::enabled() {
+        Some(agent::Watched {
+            dir: project_dir_on_remote_machine.to_string(),
+            exclude: ignore.as_ref().map(Ignore::pull).unwrap_or_default(),
+            paths: config.pull.paths.clone(),
+        })
+    } else {
+        None
+    };
+
+    let result = session.exec(
+        &script,
+        timeout,
+        watch,
+        |stream, chunk| {
+            let _ = match stream {
+                Stream::Stdout => message.write_all(chunk),
+                Stream::Stderr => err_message.write_all(chunk),
+            };
+        },
+        |paths| {
+            tracing::debug!("Changed on the remote machine: {}", paths.join(", "));
+            events::emit(&Event::RemoteChanged {
+                host: &config.remote.host,
+                paths: &paths,
+            });
+        },
+    );
+
+    message.finish();
+    err_message.finish();
+
+    match result {
+        Err(error) => {
+            tracing::error!("{}", error);
+            Err(RemoteCommandErr {
+                duration: start_time.elapsed(),
+                exit_code: None,
+                timed_out: false,
+            })
+        }
+        Ok((Some(0), false)) => Ok(RemoteCommandOk {
+            duration: start_time.elapsed(),
+        }),
+        Ok((exit_code, timed_out)) => Err(RemoteCommandErr {
+            duration: start_time.elapsed(),
+            exit_code,
+            timed_out,
+        }),
+    }
+}
+
+fn rewriter(
+    config: &Config,
+    local_dir_absolute_path: &Path,
+    project_dir_on_remote_machine: &str,
+) -> Option<PathRewriter> {
+    if config.remote.rewrite_paths {
+        Some(PathRewriter::new(
+            &local_dir_absolute_path.to_string_lossy(),
+            project_dir_on_remote_machine,
+            &config.remote.path_mappings,
+        ))
+    } else {
+        None
+    }
+}
+
+/// Runs a hook command on the remote machine, output gets logged like the one of the remote command.
+pub fn execute_hook(
+    command: &str,
+    config: &Config,
+    project_dir_on_remote_machine: &str,
+    env: Vec<(String, String)>,
+) -> Result<(), RemoteCommandErr> {
+    let env = remote_env(&config.remote, std::env::vars(), env);
+
+    _execute_remote_command(
+        command,
+        config,
+        project_dir_on_remote_machine,
+        &env,
+        None,
+        None,
+        false,
+    )
+    .map(|_| ())
+}
+
+/// Variables for the remote command: `remote.env`, forwarded local ones and then Mainframer ones,
+/// later ones win.
+pub fn remote_env(
+    remote: &Remote,
+    local_env: impl Iterator<Item = (String, String)>,
+    env: Vec<(String, String)>,
+) -> Vec<(String, String)> {
+    let mut forwarded: Vec<(String, String)> = local_env
+        .filter(|(name, _)| shell::is_variable_name(name))
+        .filter(|(name, _)| {
+            remote
+                .forward_env
+                .iter()
+                .any(|pattern| matches_glob(pattern, name))
+        })
+        .collect();
+    forwarded.sort();
+
+    remote
+        .env
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .chain(forwarded)
+        .chain(env)
+        .collect()
+}
+
+/// Matches the name against a pattern with `*` for any characters and `?` for a single one.
+fn matches_glob(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Position after the last `*` in the pattern and the name position it matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            star = Some((star_p, star_n + 1));
+            p = star_p;
+            n = star_n + 1;
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Rewrites remote project paths in the command output to local ones so IDEs can open them.
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct PathRewriter {
+    local_dir: String,
+    /// Remote prefix to local one, longest remote prefix goes first.
+    mappings: Vec<(String, String)>,
+}
+
+impl PathRewriter {
+    pub fn new(
+        local_dir: &str,
+        project_dir_on_remote_machine: &str,
+        path_mappings: &[PathMapping],
+    ) -> Self {
+        let mut rewriter = Self {
+            local_dir: local_dir.to_string(),
+            mappings: Vec::new(),
+        };
+
+        // Paths with `~` can't appear in the output as is, the resolved ones come from the remote machine.
+        if !project_dir_on_remote_machine.starts_with('~') {
+            rewriter.add(project_dir_on_remote_machine, local_dir);
+        }
+
+        for mapping in path_mappings {
+            rewriter.add(&mapping.remote, &mapping.local);
+        }
+
+        rewriter
+    }
+
+    /// Adds the project directory as resolved on the remote machine.
+    fn add_remote_dir(&mut self, remote_dir: &str) {
+        let local_dir = self.local_dir.clone();
+        self.add(remote_dir, &local_dir);
+    }
+
+    fn add(&mut self, remote: &str, local: &str) {
+        let remote = remote.trim_end_matches('/');
+        let local = local.trim_end_matches('/');
+
+        if remote.is_empty() || self.mappings.iter().any(|(known, _)| known == remote) {
+            return;
+        }
+
+        self.mappings.push((remote.to_string(), local.to_string()));
+        self.mappings
+            .sort_by_key(|(remote, _)| std::cmp::Reverse(remote.len()));
+    }
+
+    pub fn rewrite(&self, line: &str) -> String {
+        let mut result = String::with_capacity(line.len());
+        let mut rest = line;
+
+        'scan: while !rest.is_empty() {
+            for (remote, local) in &self.mappings {
+                if rest.starts_with(remote.as_str()) && is_path_boundary(&rest[remote.len()..]) {
+                    result.push_str(local);
+                    rest = &rest[remote.len()..];
+                    continue 'scan;
+                }
+            }
+
+            let next = rest.chars().next().map_or(1, char::len_utf8);
+            result.push_str(&rest[..next]);
+            rest = &rest[next..];
+        }
+
+        result
+    }
+}
+
+/// Whether the matched prefix ends a path component, `/project` should not match `/project2`.
+fn is_path_boundary(rest: &str) -> bool {
+    match rest.chars().next() {
+        None => true,
+        Some(c) => !(c.is_alphanumeric() || c == '_' || c == '-' || c == '.'),
+    }
+}
+
+/// Logs remote command output line by line.
+struct Message {
+    host: String,
+    stream: Stream,
+    rewriter: Option<Arc<RwLock<PathRewriter>>>,
+    /// Incomplete line, output chunks don't respect line boundaries.
+    pending: Vec<u8>,
+}
+
+impl Message {
+    fn line(&mut self, line: &str) {
+        if let Some(remote_dir) = line.strip_prefix(REMOTE_DIR_MARKER) {
+            if let Some(rewriter) = &self.rewriter {
+                rewriter.write().unwrap().add_remote_dir(remote_dir);
+            }
+            return;
+        }
+
+        if line.is_empty() {
+            return;
+        }
+
+        let line = match &self.rewriter {
+            None => line.to_string(),
+            Some(rewriter) => rewriter.read().unwrap().rewrite(line),
+        };
+
+        tracing::info!("{}", line);
+        events::emit(&Event::RemoteOutput {
+            host: &self.host,
+            stream: self.stream,
+            line: &line,
+        });
+
+        if let Some(captured) = CAPTURED.lock().unwrap().as_mut() {
+            captured.push(OutputLine {
+                stream: self.stream,
+                line,
+            });
+        }
+    }
+
+    /// Logs the last line if the output didn't end with a line break.
+    fn finish(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        self.line(&String::from_utf8_lossy(&pending));
+    }
+}
 
 impl Write for Message {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let buffer_string = String::from_utf8_lossy(buf);
-        let split_string = buffer_string.split('\n');
-        for s in split_string {
-            if s.is_empty() || s == "\n" {
-                continue;
-            }
-            tracing::info!("{}", s);
+        self.pending.extend_from_slice(buf);
+
+        while let Some(position) = self.pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=position).collect();
+            let line = String::from_utf8_lossy(&line[..line.len() - 1]);
+            self.line(line.trim_end_matches('\r'));
         }
+
         Ok(buf.len())
     }
 
@@ -68,47 +472,109 @@ fn _execute_remote_command(
     remote_command: &str,
     config: &Config,
     project_dir_on_remote_machine: &str,
+    env: &[(String, String)],
+    rewriter: Option<PathRewriter>,
+    timeout: Option<Duration>,
+    stdin: bool,
 ) -> Result<RemoteCommandOk, RemoteCommandErr> {
     let start_time = Instant::now();
 
-    let mut command = Command::new("ssh");
+    let destination = ssh::destination(&config.remote.host, config.remote.user.as_ref());
+    let mut command = ssh::command(&config.remote, &destination);
 
-    if let Some(port) = &config.remote.port {
-        command.arg(format!("-p {port}"));
-    }
+    command.arg(&destination);
 
-    if let Some(user) = &config.remote.user {
-        command.arg(format!("{}@{}", user, config.remote.host.clone()));
-    } else {
-        command.arg(config.remote.host.clone());
-    }
+    let script = |env: &[(String, String)]| {
+        remote_script(
+            remote_command,
+            project_dir_on_remote_machine,
+            env,
+            rewriter.is_some(),
+            timeout.is_some(),
+        )
+    };
 
-    command
-        .arg(format!(
-            "echo 'set -e && cd {project_dir_on_remote_machine} && echo \"{remote_command}\" && echo \"\" && {remote_command}' | bash",
-            project_dir_on_remote_machine = project_dir_on_remote_machine,
-            remote_command = remote_command)
-        );
+    // Values might be secrets, names are enough for the log.
+    let redacted: Vec<(String, String)> = env
+        .iter()
+        .map(|(name, _)| (name.clone(), String::from(REDACTED)))
+        .collect();
+    tracing::debug!(
+        "Executing remote command: {:?} {}",
+        command,
+        script(&redacted)
+    );
+
+    command.arg(format!("bash -c {}", shell::quote(&script(env))));
 
-    let mut process = command
+    command
+        // Kept open with a timeout, closing it kills the command on the remote machine.
+        .stdin(match (timeout, stdin) {
+            (Some(_), _) => Stdio::piped(),
+            (None, true) => Stdio::inherit(),
+            (None, false) => Stdio::null(),
+        })
         // Interactively pipe ssh output to Mainframer output.
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .unwrap();
+        .stderr(Stdio::piped());
+
+    let mut child = process::spawn(&mut command, timeout).unwrap();
+
+    let stdin = child.stdin.take();
+    let rewriter = rewriter.map(|rewriter| Arc::new(RwLock::new(rewriter)));
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let mut err_message = Message {
+        host: config.remote.host.clone(),
+        stream: Stream::Stderr,
+        rewriter: rewriter.clone(),
+        pending: Vec::new(),
+    };
+    let mut message = Message {
+        host: config.remote.host.clone(),
+        stream: Stream::Stdout,
+        rewriter,
+        pending: Vec::new(),
+    };
+    let span = tracing::Span::current();
 
-    let mut message = Message;
-    io::copy(&mut process.stdout.take().unwrap(), &mut message)
+    // Read both streams concurrently to keep the output interactive and ssh unblocked.
+    let stdout_copied = thread::spawn({
+        let span = span.clone();
+        move || {
+            let _guard = span.enter();
+            io::copy(&mut stdout, &mut message).expect("Couldn't copy ssh command's stdout");
+            message.finish();
+        }
+    });
+    let stderr_copied = thread::spawn(move || {
+        let _guard = span.enter();
+        io::copy(&mut stderr, &mut err_message).expect("Couldn't copy ssh command's stderr");
+        err_message.finish();
+    });
+
+    let status = process::wait(&mut child, timeout);
+    drop(stdin);
+
+    stdout_copied
+        .join()
         .expect("Couldn't copy ssh command's stdout");
-    let mut err_message = Message;
-    io::copy(&mut process.stderr.take().unwrap(), &mut err_message)
+    stderr_copied
+        .join()
         .expect("Couldn't copy ssh command's stderr");
 
-    match process.wait() {
+    match status {
         Err(_) => Err(RemoteCommandErr {
             duration: start_time.elapsed(),
+            exit_code: None,
+            timed_out: false,
         }), // No need to get error description as we've already piped command output to Mainframer output.
-        Ok(exit_status) => {
+        Ok(None) => Err(RemoteCommandErr {
+            duration: start_time.elapsed(),
+            exit_code: None,
+            timed_out: true,
+        }),
+        Ok(Some(exit_status)) => {
             if exit_status.success() {
                 Ok(RemoteCommandOk {
                     duration: start_time.elapsed(),
@@ -116,8 +582,197 @@ fn _execute_remote_command(
             } else {
                 Err(RemoteCommandErr {
                     duration: start_time.elapsed(),
+                    exit_code: exit_status.code(),
+                    timed_out: false,
                 })
             }
         }
     }
 }
+
+/// Script for `bash` on the remote machine, prints the command before running it.
+///
+/// With `announce_dir` the resolved project directory gets printed with [`REMOTE_DIR_MARKER`].
+/// With `kill_on_disconnect` the command runs in its own process group which gets killed
+/// once ssh standard input is closed, the remote machine doesn't do that on its own without a terminal.
+fn remote_script(
+    remote_command: &str,
+    project_dir_on_remote_machine: &str,
+    env: &[(String, String)],
+    announce_dir: bool,
+    kill_on_disconnect: bool,
+) -> String {
+    let exports: String = env
+        .iter()
+        .map(|(name, value)| format!("export {}={} && ", name, shell::quote(value)))
+        .collect();
+
+    let announce = if announce_dir {
+        format!(
+            "echo \"{marker}$(pwd)\" && echo \"{marker}$(pwd -P)\" && ",
+            marker = REMOTE_DIR_MARKER
+        )
+    } else {
+        String::new()
+    };
+
+    let run = if kill_on_disconnect {
+        format!(
+            "set +e -m; bash -c {command} </dev/null & command_pid=$!; \
+             {{ cat >/dev/null; kill -KILL -- -$command_pid 2>/dev/null; }} & watcher_pid=$!; \
+             wait $command_pid; status=$?; kill -- -$watcher_pid 2>/dev/null; exit $status",
+            command = shell::quote(remote_command)
+        )
+    } else {
+        remote_command.to_string()
+    };
+
+    format!(
+        "set -e && {exports}cd {project_dir_on_remote_machine} && {announce}echo {echo} && echo \"\" && {run}",
+        echo = shell::quote(remote_command)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_script_without_env() {
+        assert_eq!(
+            remote_script("make test", "~/mainframer/project", &[], false, false),
+            "set -e && cd ~/mainframer/project && echo 'make test' && echo \"\" && make test"
+        );
+    }
+
+    #[test]
+    fn remote_script_with_env() {
+        assert_eq!(
+            remote_script(
+                "make",
+                "~/mainframer/project",
+                &[
+                    (String::from("MAINFRAMER_SHARD_INDEX"), String::from("0")),
+                    (String::from("NAME"), String::from("a b")),
+                ],
+                false,
+                false
+            ),
+            "set -e && export MAINFRAMER_SHARD_INDEX=0 && export NAME='a b' && cd ~/mainframer/project && echo make && echo \"\" && make"
+        );
+    }
+
+    #[test]
+    fn remote_script_announces_dir() {
+        assert_eq!(
+            remote_script("make", "~/mainframer/project", &[], true, false),
+            "set -e && cd ~/mainframer/project && echo \"__MAINFRAMER_REMOTE_DIR__:$(pwd)\" && echo \"__MAINFRAMER_REMOTE_DIR__:$(pwd -P)\" && echo make && echo \"\" && make"
+        );
+    }
+
+    #[test]
+    fn remote_script_kill_on_disconnect() {
+        assert_eq!(
+            remote_script("make test", "~/mainframer/project", &[], false, true),
+            "set -e && cd ~/mainframer/project && echo 'make test' && echo \"\" && set +e -m; bash -c 'make test' </dev/null & command_pid=$!; { cat >/dev/null; kill -KILL -- -$command_pid 2>/dev/null; } & watcher_pid=$!; wait $command_pid; status=$?; kill -- -$watcher_pid 2>/dev/null; exit $status"
+        );
+    }
+
+    #[test]
+    fn matches_glob_patterns() {
+        assert!(matches_glob("CI_*", "CI_JOB_ID"));
+        assert!(matches_glob("CI_*", "CI_"));
+        assert!(matches_glob("*_TOKEN", "GITHUB_TOKEN"));
+        assert!(matches_glob("GRADLE_OPTS", "GRADLE_OPTS"));
+        assert!(matches_glob("JAVA_?OME", "JAVA_HOME"));
+        assert!(matches_glob("*A*B*", "xxAyyBzz"));
+        assert!(!matches_glob("CI_*", "GITLAB_CI"));
+        assert!(!matches_glob("GRADLE_OPTS", "GRADLE_OPTS_EXTRA"));
+        assert!(!matches_glob("JAVA_?OME", "JAVA_OME"));
+    }
+
+    #[test]
+    fn remote_env_order() {
+        let remote = Remote {
+            env: [(String::from("LANG"), String::from("C.UTF-8"))]
+                .into_iter()
+                .collect(),
+            forward_env: vec![String::from("CI_*"), String::from("GRADLE_OPTS")],
+            ..Default::default()
+        };
+        let local_env = vec![
+            (String::from("HOME"), String::from("/Users/me")),
+            (String::from("CI_JOB_ID"), String::from("42")),
+            (String::from("CI_COMMIT"), String::from("abc")),
+            (String::from("CI_BROKEN-NAME"), String::from("x")),
+            (String::from("GRADLE_OPTS"), String::from("-Xmx4g")),
+        ];
+
+        assert_eq!(
+            remote_env(
+                &remote,
+                local_env.into_iter(),
+                vec![(String::from("MAINFRAMER_SHARD_INDEX"), String::from("0"))]
+            ),
+            vec![
+                (String::from("LANG"), String::from("C.UTF-8")),
+                (String::from("CI_COMMIT"), String::from("abc")),
+                (String::from("CI_JOB_ID"), String::from("42")),
+                (String::from("GRADLE_OPTS"), String::from("-Xmx4g")),
+                (String::from("MAINFRAMER_SHARD_INDEX"), String::from("0")),
+            ]
+        );
+    }
+
+    fn rewriter() -> PathRewriter {
+        let mut rewriter = PathRewriter::new(
+            "/Users/me/project",
+            "~/mainframer/Users/me/project",
+            &[PathMapping {
+                remote: String::from("/opt/android-sdk/"),
+                local: String::from("/Users/me/Library/Android/sdk"),
+            }],
+        );
+        rewriter.add_remote_dir("/home/ci/mainframer/Users/me/project");
+        rewriter.add_remote_dir("/mnt/data/ci/mainframer/Users/me/project");
+        rewriter
+    }
+
+    #[test]
+    fn path_rewriter_rewrites_resolved_project_dir() {
+        assert_eq!(
+            rewriter()
+                .rewrite("e: /home/ci/mainframer/Users/me/project/src/Main.kt: (1, 2): error"),
+            "e: /Users/me/project/src/Main.kt: (1, 2): error"
+        );
+        assert_eq!(
+            rewriter().rewrite("at /mnt/data/ci/mainframer/Users/me/project/build.gradle:3"),
+            "at /Users/me/project/build.gradle:3"
+        );
+    }
+
+    #[test]
+    fn path_rewriter_rewrites_custom_mappings() {
+        assert_eq!(
+            rewriter().rewrite("/opt/android-sdk/platforms/android-33/android.jar"),
+            "/Users/me/Library/Android/sdk/platforms/android-33/android.jar"
+        );
+    }
+
+    #[test]
+    fn path_rewriter_respects_path_boundaries() {
+        assert_eq!(
+            rewriter().rewrite("/home/ci/mainframer/Users/me/project2/file"),
+            "/home/ci/mainframer/Users/me/project2/file"
+        );
+    }
+
+    #[test]
+    fn path_rewriter_absolute_remote_path() {
+        let rewriter = PathRewriter::new("/Users/me/project", "/builds/project", &[]);
+        assert_eq!(
+            rewriter.rewrite("'/builds/project' and /builds/project/a"),
+            "'/Users/me/project' and /Users/me/project/a"
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::{Config, Step};
+use crate::events::{self, Event};
+use crate::history;
+use crate::ignore::Ignore;
+use crate::manifest;
+use crate::remote_command::{self, OutputLine};
+
+/// Successful run of a cached task, its files are next to it in `files/`.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    pub command: String,
+    pub host: String,
+    pub created_ms: u64,
+    last_used_ms: u64,
+    /// Bytes of the stored files.
+    size: u64,
+    output: Vec<OutputLine>,
+    /// Files the run has created or changed locally.
+    artifacts: Vec<String>,
+    /// Files the run has deleted locally.
+    deleted: Vec<String>,
+}
+
+/// Key of a run and the local files it might change, before the run.
+pub struct Lookup {
+    key: String,
+    outputs: BTreeMap<String, String>,
+}
+
+/// Looks up the result of a previous run with the same steps, variables and pushed files.
+pub fn lookup(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    steps: &[Step],
+) -> Result<(Lookup, Option<Entry>), String> {
+    let push_exclude = ignore.as_ref().map(Ignore::push).unwrap_or_default();
+    let pull_exclude = ignore.as_ref().map(Ignore::pull).unwrap_or_default();
+
+    let inputs = manifest::scan(local_dir_absolute_path, &push_exclude, &[])?;
+    let outputs = manifest::scan(local_dir_absolute_path, &pull_exclude, &config.pull.paths)?;
+    let env = remote_command::remote_env(&config.remote, std::env::vars(), Vec::new());
+
+    let key = key(steps, &env, &config.pull.paths, &pull_exclude, &inputs);
+
+    let entry = match load(&dir(local_dir_absolute_path, &key)) {
+        None => None,
+        Some(mut entry) => {
+            entry.last_used_ms = history::now_ms();
+            // Failing to update the time only makes the entry older for the eviction.
+            let _ = save(&dir(local_dir_absolute_path, &key), &entry);
+            Some(entry)
+        }
+    };
+
+    Ok((Lookup { key, outputs }, entry))
+}
+
+/// Logs the output of the cached run and restores the files it has changed.
+pub fn replay(
+    local_dir_absolute_path: &Path,
+    lookup: &Lookup,
+    entry: &Entry,
+) -> Result<(), String> {
+    for line in &entry.output {
+        tracing::info!("{}", line.line);
+        events::emit(&Event::RemoteOutput {
+            host: &entry.host,
+            stream: line.stream,
+            line: &line.line,
+        });
+    }
+
+    let files = dir(local_dir_absolute_path, &lookup.key).join("files");
+
+    for path in &entry.artifacts {
+        copy(&files.join(path), &local_dir_absolute_path.join(path))
+            .map_err(|error| format!("Could not restore {}: {}", path, error))?;
+    }
+
+    for path in &entry.deleted {
+        match fs::remove_file(local_dir_absolute_path.join(path)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                return Err(format!("Could not delete {}: {}", path, error))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Stores the result of a successful run, evicting least recently used ones above `cache.maxSize`.
+pub fn store(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    lookup: Lookup,
+    record: &history::Record,
+    output: Vec<OutputLine>,
+) -> Result<(), String> {
+    let pull_exclude = ignore.as_ref().map(Ignore::pull).unwrap_or_default();
+    let outputs = manifest::scan(local_dir_absolute_path, &pull_exclude, &config.pull.paths)?;
+
+    let artifacts: Vec<String> = outputs
+        .iter()
+        .filter(|(path, hash)| lookup.outputs.get(*path) != Some(hash))
+        .map(|(path, _)| path.clone())
+        .collect();
+    let deleted: Vec<String> = lookup
+        .outputs
+        .keys()
+        .filter(|path| !outputs.contains_key(*path))
+        .cloned()
+        .collect();
+
+    let dir = dir(local_dir_absolute_path, &lookup.key);
+    let files = dir.join("files");
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut size = 0;
+
+    for path in &artifacts {
+        size += copy(&local_dir_absolute_path.join(path), &files.join(path))
+            .map_err(|error| format!("Could not store {}: {}", path, error))?;
+    }
+
+    let max_size = config.cache.max_size * 1024 * 1024;
+
+    if size > max_size {
+        let _ = fs::remove_dir_all(&dir);
+        tracing::debug!("Result is larger than 'cache.maxSize', not cached.");
+        return Ok(());
+    }
+
+    let now = history::now_ms();
+
+    save(
+        &dir,
+        &Entry {
+            command: record.command.clone(),
+            host: record.host.clone(),
+            created_ms: now,
+            last_used_ms: now,
+            size,
+            output,
+            artifacts,
+            deleted,
+        },
+    )?;
+
+    evict(local_dir_absolute_path, max_size);
+
+    Ok(())
+}
+
+fn key(
+    steps: &[Step],
+    env: &[(String, String)],
+    pull_paths: &[String],
+    pull_exclude: &[String],
+    inputs: &BTreeMap<String, String>,
+) -> String {
+    let mut hasher = Sha256::new();
+
+    hasher.update(format!("{:?}\0", steps));
+    hasher.update(format!("{:?}\0", env));
+    hasher.update(format!("{:?}\0{:?}\0", pull_paths, pull_exclude));
+
+    for (path, hash) in inputs {
+        hasher.update(format!("{}\0{}\0", path, hash));
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Removes least recently used entries until all of them fit into `max_size` bytes.
+fn evict(local_dir_absolute_path: &Path, max_size: u64) {
+    let mut entries: Vec<(PathBuf, Entry)> = match fs::read_dir(root(local_dir_absolute_path)) {
+        Err(_) => return,
+        Ok(items) => items
+            .flatten()
+            .filter_map(|item| load(&item.path()).map(|entry| (item.path(), entry)))
+            .collect(),
+    };
+
+    entries.sort_by_key(|(_, entry)| entry.last_used_ms);
+
+    let mut total: u64 = entries.iter().map(|(_, entry)| entry.size).sum();
+
+    for (dir, entry) in entries {
+        if total <= max_size {
+            break;
+        }
+
+        tracing::debug!("Evicting cached result of '{}'.", entry.command);
+        let _ = fs::remove_dir_all(dir);
+        total -= entry.size;
+    }
+}
+
+/// Copies a file or a symlink, creating parent directories. Returns the size of the file.
+fn copy(from: &Path, to: &Path) -> io::Result<u64> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if let Ok(metadata) = fs::symlink_metadata(to) {
+        if metadata.is_dir() {
+            fs::remove_dir_all(to)?;
+        } else {
+            fs::remove_file(to)?;
+        }
+    }
+
+    if fs::symlink_metadata(from)?.file_type().is_symlink() {
+        symlink(fs::read_link(from)?, to)?;
+        return Ok(0);
+    }
+
+    fs::copy(from, to)
+}
+
+fn root(local_dir_absolute_path: &Path) -> PathBuf {
+    local_dir_absolute_path
+        .join(".mainframer")
+        .join("cache")
+        .join("results")
+}
+
+fn dir(local_dir_absolute_path: &Path, key: &str) -> PathBuf {
+    root(local_dir_absolute_path).join(key)
+}
+
+fn load(dir: &Path) -> Option<Entry> {
+    fs::read(dir.join("entry.json"))
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+}
+
+fn save(dir: &Path, entry: &Entry) -> Result<(), String> {
+    let json = serde_json::to_vec(entry).map_err(|error| error.to_string())?;
+
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(dir.join("entry.json"), json))
+        .map_err(|error| format!("Could not write the cached result to {:?}: {}", dir, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StepAction;
+
+    fn inputs(hash: &str) -> BTreeMap<String, String> {
+        [(String::from("src/main.rs"), String::from(hash))]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn key_depends_on_inputs_and_env() {
+        let steps = Step::default_steps(String::from("./gradlew lint"));
+        let env = vec![(String::from("CI"), String::from("true"))];
+
+        let original = key(&steps, &env, &[], &[], &inputs("a"));
+
+        assert_eq!(key(&steps, &env, &[], &[], &inputs("a")), original);
+        assert_ne!(key(&steps, &env, &[], &[], &inputs("b")), original);
+        assert_ne!(key(&steps, &[], &[], &[], &inputs("a")), original);
+    }
+
+    #[test]
+    fn key_depends_on_steps() {
+        let steps = Step::default_steps(String::from("./gradlew lint"));
+        let without_pull = vec![
+            Step {
+                action: StepAction::Push,
+                continue_on_error: false,
+            },
+            Step {
+                action: StepAction::Exec(String::from("./gradlew lint")),
+                continue_on_error: false,
+            },
+        ];
+
+        assert_ne!(
+            key(&steps, &[], &[], &[], &inputs("a")),
+            key(&without_pull, &[], &[], &[], &inputs("a"))
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Remote;
+use crate::process;
+use crate::ssh;
+
+/// Compression algorithms of `rsync --version` by destination, `None` if it could not be run.
+///
+/// Asked once per process unless transfers to a destination start at the same time,
+/// every push and pull of a run goes to the same machines.
+static COMPRESS_LISTS: Mutex<BTreeMap<String, Option<Vec<String>>>> = Mutex::new(BTreeMap::new());
+
+/// Key of the local `rsync` in [`COMPRESS_LISTS`], destinations have no spaces.
+const LOCAL: &str = "local rsync";
+
+/// First algorithm of `preferred` both the local and the remote `rsync` support, `None` to leave it to `rsync`.
+///
+/// `rsync` older than 3.2 has no `--compress-choice`, its `--version` has no compress list either.
+pub fn compress_choice(remote: &Remote, destination: &str, preferred: &[String]) -> Option<String> {
+    if preferred.is_empty() {
+        return None;
+    }
+
+    let local = compress_list(LOCAL, || {
+        let mut command = Command::new("rsync");
+        command.arg("--version");
+        command
+    });
+    let remote = compress_list(destination, || {
+        let mut command = ssh::command(remote, destination);
+        command
+            .arg("-o")
+            .arg("BatchMode=yes")
+            .arg(destination)
+            .arg("rsync --version");
+        command
+    });
+
+    let choice = match (&local, &remote) {
+        (Some(local), Some(remote)) => choose(preferred, local, remote),
+        _ => None,
+    };
+
+    if choice.is_none() {
+        warn_once(
+            destination,
+            format!(
+                "None of {} is supported by both local and remote rsync, using its default compression.",
+                preferred.join(", ")
+            ),
+        );
+    }
+
+    choice
+}
+
+fn choose(preferred: &[String], local: &[String], remote: &[String]) -> Option<String> {
+    preferred
+        .iter()
+        .find(|algorithm| local.contains(algorithm) && remote.contains(algorithm))
+        .cloned()
+}
+
+fn compress_list(key: &str, command: impl FnOnce() -> Command) -> Option<Vec<String>> {
+    if let Some(list) = COMPRESS_LISTS.lock().unwrap().get(key) {
+        return list.clone();
+    }
+
+    // Not locked meanwhile, transfers to other destinations would wait for a slow one otherwise.
+    let mut command = command();
+    tracing::debug!("Checking rsync capabilities: {:?}", command);
+    let list = version_output(&mut command).map(|output| parse_compress_list(&output));
+
+    // Transfers to the same destination might have asked at the same time, the first answer stays.
+    COMPRESS_LISTS
+        .lock()
+        .unwrap()
+        .entry(key.to_string())
+        .or_insert(list)
+        .clone()
+}
+
+/// How long to wait for `rsync --version`, remote machines might be slow to connect.
+const VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn version_output(command: &mut Command) -> Option<String> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+
+    let mut child = process::spawn(command, Some(VERSION_TIMEOUT)).ok()?;
+    let mut stdout = child.stdout.take()?;
+
+    let output = thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stdout.read_to_string(&mut output);
+        output
+    });
+
+    match process::wait_with_timeout(&mut child, VERSION_TIMEOUT) {
+        Ok(Some(status)) if status.success() => output.join().ok(),
+        Ok(Some(_)) => None,
+        _ => {
+            let _ = child.kill();
+            None
+        }
+    }
+}
+
+/// Algorithms listed under "Compress list:" of `rsync --version`, empty before rsync 3.2.
+fn parse_compress_list(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .skip_while(|line| line.trim() != "Compress list:")
+        .skip(1)
+        .take_while(|line| line.starts_with(char::is_whitespace))
+        .flat_map(|line| line.split_whitespace())
+        .map(String::from)
+        .collect()
+}
+
+/// Destinations warned about already, pulls in `parallel` mode run many times.
+static WARNED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn warn_once(destination: &str, message: String) {
+    let mut warned = WARNED.lock().unwrap();
+
+    if !warned.iter().any(|warned| warned == destination) {
+        warned.push(destination.to_string());
+        tracing::warn!("{}", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERSION_3_2: &str = "rsync  version 3.2.7  protocol version 31
+Copyright (C) 1996-2022 by Andrew Tridgell, Wayne Davison, and others.
+Capabilities:
+    64-bit files, 64-bit inums, 64-bit timestamps, 64-bit long ints,
+    socketpairs, symlinks, symtimes, hardlinks, hardlink-specials,
+Checksum list:
+    xxh128 xxh3 xxh64 (xxhash) md5 md4 sha1 none
+Compress list:
+    zstd lz4 zlibx zlib none
+Daemon auth list:
+    sha512 sha256 sha1 md5 md4
+";
+
+    const VERSION_3_1: &str = "rsync  version 3.1.3  protocol version 31
+Copyright (C) 1996-2018 by Andrew Tridgell, Wayne Davison, and others.
+Capabilities:
+    64-bit files, 64-bit inums, 64-bit timestamps, 64-bit long ints,
+";
+
+    fn list(algorithms: &[&str]) -> Vec<String> {
+        algorithms.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_compress_list_3_2() {
+        assert_eq!(
+            parse_compress_list(VERSION_3_2),
+            list(&["zstd", "lz4", "zlibx", "zlib", "none"])
+        );
+    }
+
+    #[test]
+    fn parse_compress_list_3_1() {
+        assert_eq!(parse_compress_list(VERSION_3_1), Vec::<String>::new());
+    }
+
+    #[test]
+    fn compress_list_not_locked_while_asking() {
+        let asked = compress_list("slow.example", || {
+            // Would deadlock if the lock was still held.
+            assert!(COMPRESS_LISTS.try_lock().is_ok());
+
+            let mut command = Command::new("printf");
+            command.arg("Compress list:\n    zstd\n");
+            command
+        });
+
+        assert_eq!(asked, Some(list(&["zstd"])));
+        assert_eq!(
+            compress_list("slow.example", || unreachable!()),
+            Some(list(&["zstd"]))
+        );
+    }
+
+    #[test]
+    fn choose_first_supported_by_both() {
+        let local = list(&["zstd", "lz4", "zlib"]);
+        let remote = list(&["lz4", "zlib"]);
+
+        assert_eq!(
+            choose(&list(&["zstd", "lz4"]), &local, &remote),
+            Some(String::from("lz4"))
+        );
+        assert_eq!(choose(&list(&["zstd"]), &local, &remote), None);
+    }
+}
@@ -0,0 +1,59 @@
+/// Quotes the value so POSIX shells treat it as a single word without expansions.
+pub fn quote(value: &str) -> String {
+    let safe = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+
+    if safe {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// Whether the name can be used for a shell variable.
+pub fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_safe_value() {
+        assert_eq!(quote("/home/user/file.txt"), "/home/user/file.txt");
+    }
+
+    #[test]
+    fn quote_empty_value() {
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn quote_value_with_spaces_and_expansions() {
+        assert_eq!(quote("a b $HOME"), "'a b $HOME'");
+    }
+
+    #[test]
+    fn quote_value_with_single_quotes() {
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn is_variable_name_valid() {
+        assert!(is_variable_name("CI_JOB_ID"));
+        assert!(is_variable_name("_private1"));
+    }
+
+    #[test]
+    fn is_variable_name_invalid() {
+        assert!(!is_variable_name(""));
+        assert!(!is_variable_name("1ST"));
+        assert!(!is_variable_name("NAME-WITH-DASH"));
+    }
+}
@@ -0,0 +1,325 @@
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::config::{Config, Remote};
+
+/// How long a master connection outlives Mainframer if it could not close it, seconds.
+const CONTROL_PERSIST: u64 = 300;
+
+/// Control sockets of open master connections by destination.
+static SOCKETS: Mutex<Vec<(String, PathBuf)>> = Mutex::new(Vec::new());
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates an `ssh` command with options from the remote machine settings, destination is up to the caller.
+///
+/// The command goes through the master connection to the destination if there is one.
+pub fn command(remote: &Remote, destination: &str) -> Command {
+    let mut command = Command::new(program(remote));
+    command.args(options(remote, destination));
+    command
+}
+
+/// Value for the rsync `-e` option, `None` if the default `ssh` invocation is enough.
+pub fn rsync_shell(remote: &Remote, destination: &str) -> Option<String> {
+    let options = options(remote, destination);
+
+    if remote.ssh_command.is_none() && options.is_empty() {
+        return None;
+    }
+
+    let shell: Vec<String> = std::iter::once(program(remote).to_string())
+        .chain(options)
+        .map(|arg| rsync_quote(&arg))
+        .collect();
+
+    Some(format!("-e {}", shell.join(" ")))
+}
+
+fn program(remote: &Remote) -> &str {
+    remote.ssh_command.as_deref().unwrap_or("ssh")
+}
+
+/// Same options for every `ssh` invocation, directly or by `rsync`.
+fn options(remote: &Remote, destination: &str) -> Vec<String> {
+    let mut options: Vec<String> = Vec::new();
+
+    if let Some(port) = &remote.port {
+        options.push(String::from("-p"));
+        options.push(port.clone());
+    }
+
+    if let Some(identity_file) = &remote.identity_file {
+        options.push(String::from("-i"));
+        options.push(identity_file.clone());
+    }
+
+    if let Some(proxy_jump) = &remote.proxy_jump {
+        options.push(String::from("-J"));
+        options.push(proxy_jump.clone());
+    }
+
+    for option in &remote.ssh_options {
+        options.push(String::from("-o"));
+        options.push(option.clone());
+    }
+
+    if let Some(socket) = control_path(destination) {
+        options.push(String::from("-o"));
+        options.push(format!("ControlPath={}", socket.to_string_lossy()));
+    }
+
+    options
+}
+
+/// Quotes an argument of the rsync `-e` command, rsync splits it on spaces
+/// and understands quotes but not backslashes.
+fn rsync_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%~".contains(c));
+
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "''"))
+    }
+}
+
+pub fn destination(host: &str, user: Option<&String>) -> String {
+    match user {
+        Some(user) => format!("{user}@{host}"),
+        None => host.to_string(),
+    }
+}
+
+fn control_path(destination: &str) -> Option<PathBuf> {
+    SOCKETS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(known, _)| known == destination)
+        .map(|(_, socket)| socket.clone())
+}
+
+/// Master connections shared by all `ssh` and `rsync` invocations to the remote machine,
+/// closed once dropped.
+pub struct Connections {
+    remote: Remote,
+    dir: PathBuf,
+    destinations: Vec<String>,
+}
+
+impl Connections {
+    /// Opens master connections for the push, remote command and pull destinations,
+    /// `None` if multiplexing is off. Destinations without a master connection get connected to separately.
+    pub fn open(config: &Config) -> Option<Self> {
+        if !config.remote.multiplex {
+            return None;
+        }
+
+        // Short private directory, socket paths are limited to about 100 characters.
+        let dir = env::temp_dir().join(format!(
+            "mainframer-{}-{}",
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        if let Err(error) = DirBuilder::new().mode(0o700).create(&dir) {
+            tracing::warn!("Could not create {:?} for ssh sockets: {}", dir, error);
+            return None;
+        }
+
+        let mut connections = Self {
+            remote: config.remote.clone(),
+            dir,
+            destinations: Vec::new(),
+        };
+
+        let users = [&config.push.user, &config.remote.user, &config.pull.user];
+
+        for user in users {
+            let destination = destination(&config.remote.host, user.as_ref());
+
+            if !connections.destinations.contains(&destination) {
+                connections.open_master(destination);
+            }
+        }
+
+        Some(connections)
+    }
+
+    fn open_master(&mut self, destination: String) {
+        let socket = self.dir.join(self.destinations.len().to_string());
+
+        let mut command = command(&self.remote, &destination);
+
+        command
+            .arg("-o")
+            .arg("ControlMaster=yes")
+            .arg("-o")
+            .arg(format!("ControlPath={}", socket.to_string_lossy()))
+            .arg("-o")
+            .arg(format!("ControlPersist={}", CONTROL_PERSIST))
+            .arg("-o")
+            .arg(format!(
+                "ConnectTimeout={}",
+                self.remote.probe_timeout.max(1)
+            ))
+            .arg("-N")
+            .arg("-f")
+            .arg(&destination)
+            // Backgrounded master keeps the streams, they must not be pipes we wait on.
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+
+        tracing::debug!("Opening ssh master connection: {:?}", command);
+
+        match command.status() {
+            Ok(status) if status.success() => {
+                SOCKETS.lock().unwrap().push((destination.clone(), socket));
+                self.destinations.push(destination);
+            }
+            _ => tracing::warn!(
+                "Could not open ssh master connection to {}, connecting separately.",
+                destination
+            ),
+        }
+    }
+}
+
+impl Drop for Connections {
+    fn drop(&mut self) {
+        for destination in &self.destinations {
+            let mut command = command(&self.remote, destination);
+
+            command
+                .arg("-O")
+                .arg("exit")
+                .arg(destination)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null());
+
+            tracing::debug!("Closing ssh master connection: {:?}", command);
+
+            let _ = command.status();
+
+            SOCKETS
+                .lock()
+                .unwrap()
+                .retain(|(known, _)| known != destination);
+        }
+
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rsync_shell_default() {
+        assert_eq!(rsync_shell(&Remote::default(), "computer1"), None);
+    }
+
+    #[test]
+    fn rsync_shell_port() {
+        let remote = Remote {
+            port: Some(String::from("2222")),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            rsync_shell(&remote, "computer1"),
+            Some(String::from("-e ssh -p 2222"))
+        );
+    }
+
+    #[test]
+    fn rsync_shell_control_path() {
+        SOCKETS.lock().unwrap().push((
+            String::from("user@multiplexed"),
+            PathBuf::from("/tmp/mainframer-1-0/0"),
+        ));
+
+        let multiplexed = rsync_shell(&Remote::default(), "user@multiplexed");
+        let other = rsync_shell(&Remote::default(), "multiplexed");
+
+        // Removed before asserting so a failure doesn't leave it to other tests.
+        SOCKETS
+            .lock()
+            .unwrap()
+            .retain(|(destination, _)| destination != "user@multiplexed");
+
+        assert_eq!(
+            multiplexed,
+            Some(String::from("-e ssh -o ControlPath=/tmp/mainframer-1-0/0"))
+        );
+        assert_eq!(other, None);
+        assert!(control_path("user@multiplexed").is_none());
+    }
+
+    #[test]
+    fn rsync_shell_all_options() {
+        let remote = Remote {
+            port: Some(String::from("2222")),
+            identity_file: Some(String::from("~/.ssh/ci key")),
+            proxy_jump: Some(String::from("bastion.example.com")),
+            ssh_options: vec![
+                String::from("StrictHostKeyChecking=no"),
+                String::from("UserKnownHostsFile=/dev/null"),
+            ],
+            ssh_command: Some(String::from("/usr/local/bin/ssh")),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            rsync_shell(&remote, "computer1"),
+            Some(String::from(
+                "-e /usr/local/bin/ssh -p 2222 -i '~/.ssh/ci key' -J bastion.example.com -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null"
+            ))
+        );
+    }
+
+    #[test]
+    fn rsync_shell_custom_command() {
+        let remote = Remote {
+            ssh_command: Some(String::from("tssh")),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            rsync_shell(&remote, "computer1"),
+            Some(String::from("-e tssh"))
+        );
+    }
+
+    #[test]
+    fn command_applies_options() {
+        let remote = Remote {
+            identity_file: Some(String::from("/keys/ci")),
+            proxy_jump: Some(String::from("bastion")),
+            ..Default::default()
+        };
+        let command = command(&remote, "computer1");
+
+        assert_eq!(command.get_program(), "ssh");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            vec!["-i", "/keys/ci", "-J", "bastion"]
+        );
+    }
+
+    #[test]
+    fn rsync_quote_single_quotes() {
+        assert_eq!(rsync_quote("it's"), "'it''s'");
+    }
+}
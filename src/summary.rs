@@ -0,0 +1,140 @@
+use std::time::Duration;
+
+use crate::sync::RsyncStats;
+use crate::time::format_duration;
+
+const HEADER: [&str; 7] = [
+    "Phase",
+    "Duration",
+    "Files",
+    "Transferred",
+    "Sent",
+    "Received",
+    "Speedup",
+];
+
+/// Line of the end-of-run summary table.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Row {
+    pub phase: String,
+    pub duration: Duration,
+    /// `None` for phases without file transfer, like remote command execution.
+    pub stats: Option<RsyncStats>,
+}
+
+impl Row {
+    pub fn new(phase: &str, duration: Duration, stats: Option<RsyncStats>) -> Self {
+        Self {
+            phase: phase.to_string(),
+            duration,
+            stats,
+        }
+    }
+
+    fn cells(&self) -> Vec<String> {
+        let mut cells = vec![self.phase.clone(), format_duration(self.duration)];
+
+        if let Some(stats) = &self.stats {
+            cells.push(stats.files.to_string());
+            cells.push(stats.files_transferred.to_string());
+            cells.push(format_bytes(stats.bytes_sent));
+            cells.push(format_bytes(stats.bytes_received));
+            cells.push(format!("{:.2}", stats.speedup()));
+        }
+
+        cells
+    }
+}
+
+/// Formats rows as a table with aligned columns, one string per line.
+pub fn table(rows: &[Row]) -> Vec<String> {
+    let lines: Vec<Vec<String>> = std::iter::once(HEADER.iter().map(|h| h.to_string()).collect())
+        .chain(rows.iter().map(Row::cells))
+        .collect();
+
+    let widths: Vec<usize> = (0..HEADER.len())
+        .map(|column| {
+            lines
+                .iter()
+                .filter_map(|cells| cells.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    lines
+        .iter()
+        .map(|cells| {
+            cells
+                .iter()
+                .zip(widths.iter())
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect()
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_bytes_bytes() {
+        assert_eq!(format_bytes(35), "35 B");
+    }
+
+    #[test]
+    fn format_bytes_kibibytes() {
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+    }
+
+    #[test]
+    fn format_bytes_gibibytes() {
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn table_aligns_columns() {
+        let stats = RsyncStats {
+            files: 1234,
+            files_transferred: 5,
+            total_size: 3070,
+            bytes_sent: 1500,
+            bytes_received: 35,
+        };
+
+        assert_eq!(
+            table(&[
+                Row::new("push", Duration::from_millis(1500), Some(stats)),
+                Row::new("exec", Duration::from_secs(62), None),
+            ]),
+            vec![
+                "Phase  Duration            Files  Transferred  Sent     Received  Speedup",
+                "push   1.500 seconds       1234   5            1.5 KiB  35 B      2.00",
+                "exec   1 minute 2 seconds",
+            ]
+        );
+    }
+}
@@ -1,3 +1,6 @@
+use std::env;
+use std::fmt;
+use std::fs;
 use std::io;
 use std::io::Write;
 use std::path::Path;
@@ -5,34 +8,136 @@ use std::path::PathBuf;
 use std::process::Command;
 use std::process::Stdio;
 use std::sync::mpsc::TryRecvError::*;
+use std::sync::Mutex;
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use bus::BusReader;
 use crossbeam_channel::unbounded;
 use crossbeam_channel::Receiver;
 use crossbeam_channel::Sender;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
+use crate::compression;
 use crate::config::Config;
+use crate::events::{self, Event};
+use crate::git::{self, PushState};
 use crate::ignore::Ignore;
+use crate::manifest;
+use crate::process::{self, Deadline, Transient};
 use crate::remote_command::{RemoteCommandErr, RemoteCommandOk};
+use crate::rsync;
+use crate::ssh;
+use crate::time::format_duration;
+use crate::trace;
+
+/// Local directory of the files deleted or overwritten by pulls, neither pushed nor pulled.
+pub const TRASH_DIR: &str = ".mainframer/trash";
+
+/// Paths written by Mainframer on the local machine, neither pushed nor pulled so a pull doesn't replace them
+/// with older remote copies.
+pub const LOCAL_STATE: [&str; 3] = [".mainframer/cache", ".mainframer/history.jsonl", TRASH_DIR];
+
+/// Whether the path relative to the project is written by Mainframer itself: [`LOCAL_STATE`],
+/// something inside it or the push state of the git strategy.
+pub fn is_local_state(path: &str) -> bool {
+    LOCAL_STATE
+        .iter()
+        .chain(std::iter::once(&git::STATE_FILE))
+        .any(|state| matches!(path.strip_prefix(state), Some(rest) if rest.is_empty() || rest.starts_with('/')))
+}
+
+/// Names the directory of this run in [`TRASH_DIR`], the same for every pull of the run.
+static RUN_ID: Mutex<Option<String>> = Mutex::new(None);
+
+/// Timeout of the best-effort final pull after a timed out remote command without `pull.timeout`.
+const BEST_EFFORT_PULL_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct PushOk {
     pub duration: Duration,
+    pub stats: RsyncStats,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct PushErr {
     pub duration: Duration,
     pub message: String,
+    pub timed_out: bool,
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PushStrategy {
+    /// Compare the whole project tree.
+    #[default]
+    Full,
+
+    /// Push only files changed according to git since the previous push.
+    Git,
+
+    /// Push only files which hashes changed since the previous push, according to a local manifest.
+    Manifest,
+}
+
+/// What the remote machine has after a successful push, recorded for the next one.
+enum Pushed {
+    Git(PushState),
+    Manifest(manifest::Snapshot),
+}
+
+/// How files missing on the sending side get deleted on the receiving one.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy, Deserialize)]
+#[serde(try_from = "serde_yaml::Value")]
+pub enum Delete {
+    /// `true`, while transferring.
+    #[default]
+    During,
+
+    /// `false`, files missing on the sending side are kept.
+    Never,
+
+    /// Once the transfer is done.
+    After,
+
+    /// Found while transferring and deleted once it is done.
+    Delay,
+}
+
+impl Delete {
+    /// `rsync` option, none if files are kept.
+    fn arg(&self) -> Option<&'static str> {
+        match self {
+            Delete::During => Some("--delete"),
+            Delete::Never => None,
+            Delete::After => Some("--delete-after"),
+            Delete::Delay => Some("--delete-delay"),
+        }
+    }
+}
+
+impl TryFrom<serde_yaml::Value> for Delete {
+    type Error = String;
+
+    fn try_from(value: serde_yaml::Value) -> Result<Self, Self::Error> {
+        match value {
+            serde_yaml::Value::Bool(true) => Ok(Delete::During),
+            serde_yaml::Value::Bool(false) => Ok(Delete::Never),
+            serde_yaml::Value::String(value) if value == "after" => Ok(Delete::After),
+            serde_yaml::Value::String(value) if value == "delay" => Ok(Delete::Delay),
+            value => Err(format!(
+                "delete must be true, false, 'after' or 'delay', but was {:?}",
+                value
+            )),
+        }
+    }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Deserialize)]
+#[derive(Debug, Default, Eq, PartialEq, Clone, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum PullMode {
     /// Serial, after remote command execution.
+    #[default]
     Serial,
 
     /// Parallel to remote command execution.
@@ -44,37 +149,457 @@ impl PullMode {
     pub const PARALLEL_DURATION: Duration = Duration::from_millis(500);
 }
 
-impl Default for PullMode {
-    fn default() -> Self {
-        Self::Serial
+/// Where pulled files land on the local machine.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct PullTarget {
+    pub dir: PathBuf,
+    /// Whether local files missing on the remote machine get deleted.
+    pub delete: bool,
+}
+
+impl PullTarget {
+    pub fn project(local_dir_absolute_path: &Path) -> Self {
+        Self {
+            dir: local_dir_absolute_path.to_path_buf(),
+            delete: true,
+        }
     }
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct PullOk {
+    /// Perceived duration, pulls in parallel to remote command execution are not counted.
     pub duration: Duration,
+    /// Every pull iteration, there are several of them in the parallel mode.
+    pub transfers: Vec<Transfer>,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct PullErr {
     pub duration: Duration,
     pub message: String,
+    pub timed_out: bool,
+}
+
+/// Single rsync invocation.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Transfer {
+    pub duration: Duration,
+    pub stats: RsyncStats,
+}
+
+/// Transfer statistics reported by `rsync --stats`.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RsyncStats {
+    pub files: u64,
+    pub files_transferred: u64,
+    pub total_size: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl RsyncStats {
+    pub fn parse(output: &str) -> Self {
+        let mut stats = Self::default();
+
+        for line in output.lines() {
+            let (name, value) = match line.split_once(':') {
+                None => continue,
+                Some(value) => value,
+            };
+
+            let value = parse_number(value);
+
+            match name.trim() {
+                "Number of files" => stats.files = value,
+                "Total file size" => stats.total_size = value,
+                // rsync 2.x reports "Number of files transferred".
+                "Number of regular files transferred" | "Number of files transferred" => {
+                    stats.files_transferred = value
+                }
+                "Total bytes sent" => stats.bytes_sent = value,
+                "Total bytes received" => stats.bytes_received = value,
+                _ => {}
+            }
+        }
+
+        stats
+    }
+
+    /// Total size divided by bytes on the wire, the same way rsync reports it.
+    pub fn speedup(&self) -> f64 {
+        match self.bytes_sent + self.bytes_received {
+            0 => 0.0,
+            bytes => self.total_size as f64 / bytes as f64,
+        }
+    }
+}
+
+/// Parses the leading number of a value like " 1,234 bytes".
+fn parse_number(value: &str) -> u64 {
+    value
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == ',')
+        .filter(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .unwrap_or_default()
 }
 
 pub fn push(
     local_dir_absolute_path: &Path,
     config: &Config,
     ignore: &Option<Ignore>,
+    deadline: Deadline,
     verbose: u8,
 ) -> Result<PushOk, PushErr> {
     let start_time = Instant::now();
 
+    let plan = match config.push.strategy {
+        PushStrategy::Full => Ok((None, None)),
+        PushStrategy::Git => git_push_plan(local_dir_absolute_path, config, ignore, deadline)
+            .map(|(state, files)| (Some(Pushed::Git(state)), files)),
+        PushStrategy::Manifest => manifest::plan(local_dir_absolute_path, config, ignore)
+            .map(|(snapshot, files)| (Some(Pushed::Manifest(snapshot)), files)),
+    };
+
+    let (pushed, files) = match plan {
+        Err(error) => {
+            tracing::warn!("{}, pushing all files.", error);
+            (None, None)
+        }
+        Ok(plan) => plan,
+    };
+
+    if let Some(files) = &files {
+        if files.is_empty() {
+            tracing::info!("No files changed since the previous push.");
+            return Ok(PushOk {
+                duration: start_time.elapsed(),
+                stats: RsyncStats::default(),
+            });
+        }
+
+        tracing::debug!("Pushing {} changed files.", files.len());
+    }
+
+    let result = transfer(
+        local_dir_absolute_path,
+        config,
+        ignore,
+        files.as_deref(),
+        deadline,
+        verbose,
+        start_time,
+    );
+
+    if let (Ok(_), Some(pushed)) = (&result, pushed) {
+        // The next push falls back to all files.
+        let recorded = match pushed {
+            Pushed::Git(state) => {
+                write_push_state(local_dir_absolute_path, config, &state, deadline)
+            }
+            Pushed::Manifest(snapshot) => {
+                manifest::acknowledge(local_dir_absolute_path, &config.remote.host, snapshot)
+            }
+        };
+
+        if let Err(error) = recorded {
+            tracing::warn!("Could not record the push: {}", error);
+        }
+    }
+
+    result
+}
+
+/// Pushes only the listed files, relative to the project, deleting the ones missing locally.
+pub fn push_files(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    files: &[String],
+    deadline: Deadline,
+    verbose: u8,
+) -> Result<PushOk, PushErr> {
+    transfer(
+        local_dir_absolute_path,
+        config,
+        ignore,
+        Some(files),
+        deadline,
+        verbose,
+        Instant::now(),
+    )
+}
+
+/// Runs rsync push of the listed files or of the whole project if `files` is `None`.
+fn transfer(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    files: Option<&[String]>,
+    deadline: Deadline,
+    verbose: u8,
+    start_time: Instant,
+) -> Result<PushOk, PushErr> {
+    let files_from = match files.map(|files| write_files_from(config, files)) {
+        Some(Err(error)) => {
+            return Err(PushErr {
+                duration: start_time.elapsed(),
+                message: error,
+                timed_out: false,
+            })
+        }
+        Some(Ok(path)) => Some(path),
+        None => None,
+    };
+
+    let mut command = push_command(
+        local_dir_absolute_path,
+        config,
+        ignore,
+        files_from.as_deref(),
+        false,
+        verbose,
+    );
+
+    tracing::debug!("Executing rsync push: {:?}", command);
+
+    // Without planning and retry pauses, for measuring the link.
+    let mut rsync_duration = Duration::ZERO;
+
+    let result = process::retry(
+        "Push",
+        config.push.retries,
+        Duration::from_secs(config.push.retry_backoff),
+        deadline,
+        || {
+            let timeout = deadline.timeout(config.push.timeout.map(Duration::from_secs));
+            let attempt_start = Instant::now();
+            let result = execute_rsync(&mut command, timeout);
+            rsync_duration = attempt_start.elapsed();
+            result
+        },
+    );
+
+    if let Some(files_from) = files_from {
+        let _ = fs::remove_file(files_from);
+    }
+
+    match result {
+        Err(err) => Err(PushErr {
+            duration: start_time.elapsed(),
+            message: err.message,
+            timed_out: err.timed_out,
+        }),
+        Ok(output) => {
+            let stats = RsyncStats::parse(&output);
+
+            compression::measure(
+                local_dir_absolute_path,
+                &config.remote.host,
+                config.push.compression,
+                &stats,
+                rsync_duration,
+            );
+
+            Ok(PushOk {
+                duration: start_time.elapsed(),
+                stats,
+            })
+        }
+    }
+}
+
+/// Current local state and files to push since the state recorded on the remote machine,
+/// `None` for all files if the remote state is unknown.
+fn git_push_plan(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    deadline: Deadline,
+) -> Result<(PushState, Option<Vec<String>>), String> {
+    let ignore = ignore.as_ref().map(Ignore::push).unwrap_or_default();
+    let current = PushState::current(local_dir_absolute_path, ignore)?;
+
+    let files = match read_push_state(local_dir_absolute_path, config, deadline) {
+        None => {
+            tracing::info!("State of the remote machine is unknown, pushing all files.");
+            None
+        }
+        Some(previous) => git::changed_files(local_dir_absolute_path, &previous, &current)?,
+    };
+
+    Ok((current, files))
+}
+
+/// Limited like the push itself, an unreachable machine would hold the run before it otherwise.
+fn read_push_state(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    deadline: Deadline,
+) -> Option<PushState> {
+    let destination = ssh::destination(&config.remote.host, config.push.user.as_ref());
+    let mut command = ssh::command(&config.remote, &destination);
+    let timeout = deadline.timeout(config.push.timeout.map(Duration::from_secs));
+
+    command
+        .arg(&destination)
+        .arg(format!(
+            "cat {}/{} 2>/dev/null",
+            project_dir_on_remote_machine(config, local_dir_absolute_path),
+            git::STATE_FILE
+        ))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+
+    let mut child = process::spawn(&mut command, timeout).ok()?;
+    let mut stdout = child.stdout.take()?;
+
+    // Read in background so a large state can't block on a full pipe.
+    let output = thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = io::Read::read_to_end(&mut stdout, &mut output);
+        output
+    });
+
+    match process::wait(&mut child, timeout) {
+        Ok(Some(status)) if status.success() => serde_json::from_slice(&output.join().ok()?).ok(),
+        Ok(None) => {
+            tracing::warn!(
+                "Reading the push state timed out after {}.",
+                format_duration(timeout.unwrap_or_default())
+            );
+            None
+        }
+        _ => None,
+    }
+}
+
+fn write_push_state(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    state: &PushState,
+    deadline: Deadline,
+) -> Result<(), String> {
+    let destination = ssh::destination(&config.remote.host, config.push.user.as_ref());
+    let mut command = ssh::command(&config.remote, &destination);
+    let timeout = deadline.timeout(config.push.timeout.map(Duration::from_secs));
+
+    command
+        .arg(&destination)
+        .arg(format!(
+            "cat > {}/{}",
+            project_dir_on_remote_machine(config, local_dir_absolute_path),
+            git::STATE_FILE
+        ))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    let mut child = process::spawn(&mut command, timeout).map_err(|error| error.to_string())?;
+
+    let json = serde_json::to_vec(state).map_err(|error| error.to_string())?;
+    let written = child
+        .stdin
+        .take()
+        .expect("Could not open ssh stdin")
+        .write_all(&json);
+
+    // Waited for even if writing failed, the child gets killed on timeout.
+    match (process::wait(&mut child, timeout), written) {
+        (Ok(None), _) => Err(format!(
+            "ssh timed out after {}",
+            format_duration(timeout.unwrap_or_default())
+        )),
+        (_, Err(error)) => Err(error.to_string()),
+        (Ok(Some(status)), _) if status.success() => Ok(()),
+        (Ok(Some(status)), _) => Err(format!("ssh exit status {}", status)),
+        (Err(error), _) => Err(error.to_string()),
+    }
+}
+
+/// Writes the list of files for `rsync --files-from`, separated by NUL characters.
+fn write_files_from(config: &Config, files: &[String]) -> Result<PathBuf, String> {
+    let path = env::temp_dir().join(format!(
+        "mainframer-{}-{}.files",
+        std::process::id(),
+        config.remote.host
+    ));
+
+    fs::write(&path, files.join("\0")).map_err(|error| {
+        format!(
+            "Could not write the list of files to push to {:?}: {}",
+            path, error
+        )
+    })?;
+
+    Ok(path)
+}
+
+/// Lists changes the push would make on the remote machine without making them.
+pub fn preview_push(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    deadline: Deadline,
+    verbose: u8,
+) -> Result<Vec<Change>, String> {
+    let mut command = push_command(local_dir_absolute_path, config, ignore, None, true, verbose);
+
+    tracing::debug!("Executing rsync push preview: {:?}", command);
+
+    execute_rsync(
+        &mut command,
+        deadline.timeout(config.push.timeout.map(Duration::from_secs)),
+    )
+    .map(|output| output.lines().filter_map(Change::parse).collect())
+    .map_err(|err| err.message)
+}
+
+/// Pushes the files listed in `files_from` if set, all files otherwise.
+fn push_command(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    files_from: Option<&Path>,
+    dry_run: bool,
+    verbose: u8,
+) -> Command {
+    let destination = ssh::destination(&config.remote.host, config.push.user.as_ref());
+
     let mut command = Command::new("rsync");
 
-    command.arg("--archive").arg("--delete");
+    command.arg("--archive").arg("--stats");
+
+    match files_from {
+        None => command.args(config.push.delete.arg()),
+        // Listed files missing locally have been deleted.
+        Some(files_from) => command
+            .arg(format!("--files-from={}", files_from.to_string_lossy()))
+            .arg("--from0")
+            .arg(if config.push.delete == Delete::Never {
+                "--ignore-missing-args"
+            } else {
+                "--delete-missing-args"
+            }),
+    };
+
+    // Excluded files are not deleted on the receiving side either.
+    command.arg(format!("--exclude=/{}", git::STATE_FILE));
+    apply_local_state(&mut command);
+
+    apply_protect(&mut command, &config.push.protect);
+
+    if dry_run {
+        apply_dry_run(&mut command);
+    }
 
-    if let Some(port) = &config.remote.port {
-        command.arg(format!("-e ssh -p {port}"));
+    if let Some(shell) = ssh::rsync_shell(&config.remote, &destination) {
+        command.arg(shell);
     }
 
     command
@@ -82,7 +607,22 @@ pub fn push(
             "--rsync-path=mkdir -p {} && rsync",
             project_dir_on_remote_machine(config, local_dir_absolute_path)
         ))
-        .arg(format!("--compress-level={}", config.push.compression));
+        .arg(format!(
+            "--compress-level={}",
+            compression::level(
+                local_dir_absolute_path,
+                &config.remote.host,
+                config.push.compression
+            )
+        ));
+
+    apply_tuning(
+        &mut command,
+        config.push.bandwidth_limit,
+        config.push.checksum,
+        config.push.partial,
+        rsync::compress_choice(&config.remote, &destination, &config.push.compress_choice),
+    );
 
     for i in 0..verbose {
         //Don't add more than two --verbose to rsync, unless you want to debug rsync
@@ -98,41 +638,24 @@ pub fn push(
 
     command.arg("./");
 
-    if let Some(user) = &config.push.user {
-        command.arg(format!(
-            "{user}@{remote_machine_name}:{project_dir_on_remote_machine}",
-            remote_machine_name = config.remote.host,
-            project_dir_on_remote_machine =
-                project_dir_on_remote_machine(config, local_dir_absolute_path)
-        ));
-    } else {
-        command.arg(format!(
-            "{remote_machine_name}:{project_dir_on_remote_machine}",
-            remote_machine_name = config.remote.host,
-            project_dir_on_remote_machine =
-                project_dir_on_remote_machine(config, local_dir_absolute_path)
-        ));
-    }
-
-    tracing::debug!("Executing rsync push: {:?}", command);
+    command.arg(format!(
+        "{destination}:{project_dir_on_remote_machine}",
+        project_dir_on_remote_machine =
+            project_dir_on_remote_machine(config, local_dir_absolute_path)
+    ));
 
-    match execute_rsync(&mut command) {
-        Err(reason) => Err(PushErr {
-            duration: start_time.elapsed(),
-            message: reason,
-        }),
-        Ok(_) => Ok(PushOk {
-            duration: start_time.elapsed(),
-        }),
-    }
+    command
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn pull(
     local_dir_absolute_path: &Path,
     config: Config,
     ignore: Option<Ignore>,
+    target: PullTarget,
     pull_mode: &PullMode,
     remote_command_finished_signal: BusReader<Result<RemoteCommandOk, RemoteCommandErr>>,
+    deadline: Deadline,
     verbose: u8,
 ) -> Receiver<Result<PullOk, PullErr>> {
     match pull_mode {
@@ -140,58 +663,102 @@ pub fn pull(
             local_dir_absolute_path.to_path_buf(),
             config,
             ignore,
+            target,
             remote_command_finished_signal,
+            deadline,
             verbose,
         ),
         PullMode::Parallel => pull_parallel(
             local_dir_absolute_path.to_path_buf(),
             config,
             ignore,
+            target,
             PullMode::PARALLEL_DURATION,
             remote_command_finished_signal,
+            deadline,
             verbose,
         ),
     }
 }
 
+/// Pulls once without waiting for a remote command, like a pull step on its own.
+pub fn pull_once(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    target: &PullTarget,
+    deadline: Deadline,
+    verbose: u8,
+) -> Result<PullOk, PullErr> {
+    _pull(
+        local_dir_absolute_path,
+        config,
+        ignore,
+        target,
+        deadline,
+        verbose,
+    )
+    .map(|transfer| PullOk {
+        duration: transfer.duration,
+        transfers: vec![transfer],
+    })
+}
+
 fn pull_serial(
     local_dir_absolute_path: PathBuf,
     config: Config,
     ignore: Option<Ignore>,
+    target: PullTarget,
     mut remote_command_finished_rx: BusReader<Result<RemoteCommandOk, RemoteCommandErr>>,
+    deadline: Deadline,
     verbose: u8,
 ) -> Receiver<Result<PullOk, PullErr>> {
     let (pull_finished_tx, pull_finished_rx): (
         Sender<Result<PullOk, PullErr>>,
         Receiver<Result<PullOk, PullErr>>,
     ) = unbounded();
+    let span = tracing::Span::current();
 
-    #[allow(unused_must_use)]
-    // We don't handle remote_command_result, in any case we need to pull after it.
+    // We pull after the remote command regardless of its result.
     thread::spawn(move || {
-        remote_command_finished_rx
+        let _guard = span.enter();
+
+        let remote_command_result = remote_command_finished_rx
             .recv()
             .expect("Could not receive remote_command_finished_rx");
 
+        let _pull_span = tracing::info_span!(target: trace::TARGET, "final pull").entered();
+
         pull_finished_tx
-            .send(_pull(
-                local_dir_absolute_path.as_path(),
-                &config,
-                &ignore,
-                verbose,
-            ))
+            .send(
+                _pull(
+                    local_dir_absolute_path.as_path(),
+                    &config,
+                    &ignore,
+                    &target,
+                    final_pull_deadline(&config, deadline, &remote_command_result),
+                    verbose,
+                )
+                .map(|transfer| PullOk {
+                    duration: transfer.duration,
+                    transfers: vec![transfer],
+                }),
+            )
             .expect("Could not send pull_finished signal");
     });
 
     pull_finished_rx
 }
 
+#[allow(clippy::too_many_arguments)]
 fn pull_parallel(
     local_dir_absolute_path: PathBuf,
     config: Config,
     ignore: Option<Ignore>,
+    target: PullTarget,
     pause_between_pulls: Duration,
     mut remote_command_finished_signal: BusReader<Result<RemoteCommandOk, RemoteCommandErr>>,
+    deadline: Deadline,
     verbose: u8,
 ) -> Receiver<Result<PullOk, PullErr>> {
     let (pull_finished_tx, pull_finished_rx): (
@@ -199,53 +766,96 @@ fn pull_parallel(
         Receiver<Result<PullOk, PullErr>>,
     ) = unbounded();
     let start_time = Instant::now();
+    let span = tracing::Span::current();
 
     thread::spawn(move || {
-        loop {
-            if let Err(pull_err) =
-                _pull(local_dir_absolute_path.as_path(), &config, &ignore, verbose)
-            {
-                pull_finished_tx
-                    .send(Err(pull_err)) // TODO handle code 24.
-                    .expect("Could not send pull_finished signal");
-                break;
+        let _guard = span.enter();
+        let mut transfers: Vec<Transfer> = Vec::new();
+
+        let remote_command_result = loop {
+            let pull_result = tracing::info_span!(
+                target: trace::TARGET,
+                "pull",
+                iteration = transfers.len() + 1
+            )
+            .in_scope(|| {
+                _pull(
+                    local_dir_absolute_path.as_path(),
+                    &config,
+                    &ignore,
+                    &target,
+                    deadline,
+                    verbose,
+                )
+            });
+
+            match pull_result {
+                Ok(transfer) => transfers.push(transfer),
+                // The run is out of time, the remote command is about to time out as well.
+                Err(ref pull_err) if pull_err.timed_out => {
+                    match remote_command_finished_signal.recv() {
+                        Ok(remote_command_result) => break remote_command_result,
+                        // The run stopped before the final pull, nobody waits for the result.
+                        Err(_) => return,
+                    }
+                }
+                Err(pull_err) => {
+                    pull_finished_tx
+                        .send(Err(pull_err)) // TODO handle code 24.
+                        .expect("Could not send pull_finished signal");
+                    return;
+                }
             }
 
             match remote_command_finished_signal.try_recv() {
                 Err(reason) => match reason {
-                    Disconnected => break,
+                    Disconnected => return,
                     Empty => thread::sleep(pause_between_pulls),
                 },
-                Ok(remote_command_result) => {
-                    let remote_command_duration = match remote_command_result {
-                        Err(err) => err.duration,
-                        Ok(ok) => ok.duration,
-                    };
-
-                    // Final pull after remote command to ensure consistency of the files.
-                    match _pull(local_dir_absolute_path.as_path(), &config, &ignore, verbose) {
-                        Err(err) => pull_finished_tx
-                            .send(Err(PullErr {
-                                duration: calculate_perceived_pull_duration(
-                                    start_time.elapsed(),
-                                    remote_command_duration,
-                                ),
-                                message: err.message,
-                            }))
-                            .expect("Could not send pull finished signal (last iteration)"),
-
-                        Ok(_) => pull_finished_tx
-                            .send(Ok(PullOk {
-                                duration: calculate_perceived_pull_duration(
-                                    start_time.elapsed(),
-                                    remote_command_duration,
-                                ),
-                            }))
-                            .expect("Could not send pull finished signal (last iteration)"),
-                    }
+                Ok(remote_command_result) => break remote_command_result,
+            }
+        };
 
-                    break;
-                }
+        let remote_command_duration = match remote_command_result {
+            Err(ref err) => err.duration,
+            Ok(ref ok) => ok.duration,
+        };
+
+        // Final pull after remote command to ensure consistency of the files.
+        let pull_result = tracing::info_span!(target: trace::TARGET, "final pull").in_scope(|| {
+            _pull(
+                local_dir_absolute_path.as_path(),
+                &config,
+                &ignore,
+                &target,
+                final_pull_deadline(&config, deadline, &remote_command_result),
+                verbose,
+            )
+        });
+
+        match pull_result {
+            Err(err) => pull_finished_tx
+                .send(Err(PullErr {
+                    duration: calculate_perceived_pull_duration(
+                        start_time.elapsed(),
+                        remote_command_duration,
+                    ),
+                    ..err
+                }))
+                .expect("Could not send pull finished signal (last iteration)"),
+
+            Ok(transfer) => {
+                transfers.push(transfer);
+
+                pull_finished_tx
+                    .send(Ok(PullOk {
+                        duration: calculate_perceived_pull_duration(
+                            start_time.elapsed(),
+                            remote_command_duration,
+                        ),
+                        transfers,
+                    }))
+                    .expect("Could not send pull finished signal (last iteration)")
             }
         }
     });
@@ -253,23 +863,184 @@ fn pull_parallel(
     pull_finished_rx
 }
 
+/// The final pull after a timed out command is best-effort and ignores the run deadline, partial results are still useful.
+fn final_pull_deadline(
+    config: &Config,
+    deadline: Deadline,
+    remote_command_result: &Result<RemoteCommandOk, RemoteCommandErr>,
+) -> Deadline {
+    let timeout = config.pull.timeout.map(Duration::from_secs);
+
+    match remote_command_result {
+        Err(err) if err.timed_out => Deadline::after(
+            Some(timeout.unwrap_or(BEST_EFFORT_PULL_TIMEOUT)),
+            Instant::now(),
+        ),
+        _ => deadline,
+    }
+}
+
 fn _pull(
     local_dir_absolute_path: &Path,
     config: &Config,
     ignore: &Option<Ignore>,
+    target: &PullTarget,
+    deadline: Deadline,
     verbose: u8,
-) -> Result<PullOk, PullErr> {
+) -> Result<Transfer, PullErr> {
     let start_time = Instant::now();
 
+    let mut command = pull_command(
+        local_dir_absolute_path,
+        config,
+        ignore,
+        target,
+        false,
+        verbose,
+    );
+
+    tracing::debug!("Executing rsync pull: {:?}", command);
+
+    // Without retry pauses and failed attempts, for measuring the link.
+    let mut rsync_duration = Duration::ZERO;
+
+    let result = process::retry(
+        "Pull",
+        config.pull.retries,
+        Duration::from_secs(config.pull.retry_backoff),
+        deadline,
+        || {
+            let timeout = deadline.timeout(config.pull.timeout.map(Duration::from_secs));
+            let attempt_start = Instant::now();
+            let result = execute_rsync(&mut command, timeout);
+            rsync_duration = attempt_start.elapsed();
+            result
+        },
+    );
+
+    let result = match result {
+        Err(err) => Err(PullErr {
+            duration: start_time.elapsed(),
+            message: err.message,
+            timed_out: err.timed_out,
+        }),
+        Ok(output) => Ok(Transfer {
+            duration: start_time.elapsed(),
+            stats: RsyncStats::parse(&output),
+        }),
+    };
+
+    if let Ok(ref transfer) = result {
+        compression::measure(
+            local_dir_absolute_path,
+            &config.remote.host,
+            config.pull.compression,
+            &transfer.stats,
+            rsync_duration,
+        );
+    }
+
+    events::emit(&match result {
+        Err(ref err) => Event::PullIteration {
+            host: &config.remote.host,
+            success: false,
+            duration_ms: events::millis(err.duration),
+            stats: None,
+            message: Some(&err.message),
+        },
+        Ok(ref ok) => Event::PullIteration {
+            host: &config.remote.host,
+            success: true,
+            duration_ms: events::millis(ok.duration),
+            stats: Some(&ok.stats),
+            message: None,
+        },
+    });
+
+    result
+}
+
+/// Lists changes the pull would make on the local machine without making them.
+pub fn preview_pull(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    target: &PullTarget,
+    deadline: Deadline,
+    verbose: u8,
+) -> Result<Vec<Change>, String> {
+    let mut command = pull_command(
+        local_dir_absolute_path,
+        config,
+        ignore,
+        target,
+        true,
+        verbose,
+    );
+
+    tracing::debug!("Executing rsync pull preview: {:?}", command);
+
+    execute_rsync(
+        &mut command,
+        deadline.timeout(config.pull.timeout.map(Duration::from_secs)),
+    )
+    .map(|output| output.lines().filter_map(Change::parse).collect())
+    .map_err(|err| err.message)
+}
+
+fn pull_command(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    target: &PullTarget,
+    dry_run: bool,
+    verbose: u8,
+) -> Command {
     let mut command = Command::new("rsync");
 
-    command
-        .arg("--archive")
-        .arg("--delete")
-        .arg(format!("--compress-level={}", config.pull.compression));
+    command.arg("--archive").arg("--stats");
 
-    if let Some(port) = &config.remote.port {
-        command.arg(format!("-e ssh -p {port}"));
+    if target.delete {
+        command.args(config.pull.delete.arg());
+    }
+
+    command.arg(format!("--exclude=/{}", git::STATE_FILE));
+    apply_local_state(&mut command);
+
+    apply_protect(&mut command, &config.pull.protect);
+
+    if config.pull.backup {
+        command.arg("--backup").arg(format!(
+            "--backup-dir={}",
+            trash_dir(local_dir_absolute_path).to_string_lossy()
+        ));
+    }
+
+    if dry_run {
+        apply_dry_run(&mut command);
+    }
+
+    command.arg(format!(
+        "--compress-level={}",
+        compression::level(
+            local_dir_absolute_path,
+            &config.remote.host,
+            config.pull.compression
+        )
+    ));
+
+    let destination = ssh::destination(&config.remote.host, config.pull.user.as_ref());
+
+    apply_tuning(
+        &mut command,
+        config.pull.bandwidth_limit,
+        config.pull.checksum,
+        config.pull.partial,
+        rsync::compress_choice(&config.remote, &destination, &config.pull.compress_choice),
+    );
+
+    if let Some(shell) = ssh::rsync_shell(&config.remote, &destination) {
+        command.arg(shell);
     }
 
     for i in 0..verbose {
@@ -284,35 +1055,17 @@ fn _pull(
         apply_exclude_from(&mut command, ignore.pull());
     }
 
-    if let Some(user) = &config.pull.user {
-        command.arg(format!(
-            "{user}@{remote_machine_name}:{project_dir_on_remote_machine}/",
-            remote_machine_name = config.remote.host,
-            project_dir_on_remote_machine =
-                project_dir_on_remote_machine(config, local_dir_absolute_path)
-        ));
-    } else {
-        command.arg(format!(
-            "{remote_machine_name}:{project_dir_on_remote_machine}/",
-            remote_machine_name = config.remote.host,
-            project_dir_on_remote_machine =
-                project_dir_on_remote_machine(config, local_dir_absolute_path)
-        ));
-    }
+    command.args(pull_path_filters(&config.pull.paths));
 
-    command.arg("./");
+    command.arg(format!(
+        "{destination}:{project_dir_on_remote_machine}/",
+        project_dir_on_remote_machine =
+            project_dir_on_remote_machine(config, local_dir_absolute_path)
+    ));
 
-    tracing::debug!("Executing rsync pull: {:?}", command);
+    command.arg(format!("{}/", target.dir.to_string_lossy()));
 
-    match execute_rsync(&mut command) {
-        Err(reason) => Err(PullErr {
-            duration: start_time.elapsed(),
-            message: reason,
-        }),
-        Ok(_) => Ok(PullOk {
-            duration: start_time.elapsed(),
-        }),
-    }
+    command
 }
 
 pub fn project_dir_on_remote_machine(config: &Config, local_dir_absolute_path: &Path) -> String {
@@ -323,13 +1076,151 @@ pub fn project_dir_on_remote_machine(config: &Config, local_dir_absolute_path: &
     }
 }
 
+fn apply_local_state(rsync_command: &mut Command) {
+    for path in LOCAL_STATE {
+        rsync_command.arg(format!("--exclude=/{}", path));
+    }
+}
+
+/// Receiving side doesn't delete files matching the patterns even if they are missing on the sending side.
+fn apply_protect(rsync_command: &mut Command, patterns: &[String]) {
+    for pattern in patterns {
+        rsync_command.arg(format!("--filter=P {}", pattern));
+    }
+}
+
+/// Directory of the local files a pull of this run has deleted or overwritten, see `pull.backup`.
+pub fn trash_dir(local_dir_absolute_path: &Path) -> PathBuf {
+    let mut run_id = RUN_ID.lock().unwrap();
+
+    let run_id = run_id.get_or_insert_with(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!("{}-{}", now.as_millis(), std::process::id())
+    });
+
+    local_dir_absolute_path.join(TRASH_DIR).join(run_id)
+}
+
+fn apply_tuning(
+    rsync_command: &mut Command,
+    bandwidth_limit: Option<u64>,
+    checksum: bool,
+    partial: bool,
+    compress_choice: Option<String>,
+) {
+    if let Some(limit) = bandwidth_limit {
+        rsync_command.arg(format!("--bwlimit={}", limit));
+    }
+
+    if checksum {
+        rsync_command.arg("--checksum");
+    }
+
+    if partial {
+        rsync_command.arg("--partial");
+    }
+
+    if let Some(algorithm) = compress_choice {
+        rsync_command.arg(format!("--compress-choice={}", algorithm));
+    }
+}
+
+/// Item change, file size and name, the size is `0` for deletions.
+const DRY_RUN_OUT_FORMAT: &str = "%i %l %n";
+
+fn apply_dry_run(rsync_command: &mut Command) {
+    rsync_command
+        .arg("--dry-run")
+        .arg(format!("--out-format={}", DRY_RUN_OUT_FORMAT));
+}
+
+/// File change reported by a dry run, directories are listed only when deleted.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Change {
+    /// Path and size of a new file.
+    Create(String, u64),
+    /// Path and size of a changed file, sent as a whole in the worst case.
+    Update(String, u64),
+    Delete(String),
+}
+
+impl Change {
+    /// Parses a line of the `DRY_RUN_OUT_FORMAT` output, `None` for other lines and unchanged files.
+    fn parse(line: &str) -> Option<Self> {
+        let (item, rest) = line.split_once(' ')?;
+        let (size, path) = rest.trim_start().split_once(' ')?;
+
+        if item == "*deleting" {
+            return Some(Change::Delete(path.to_string()));
+        }
+
+        let size: u64 = size.parse().ok()?;
+
+        // `YXcstpoguax`: update type, file type and attributes, `+` for a new item.
+        match item.as_bytes() {
+            [b'<' | b'>', b'f', attributes @ ..] if attributes.len() == 9 => {
+                if attributes.iter().all(|attribute| *attribute == b'+') {
+                    Some(Change::Create(path.to_string(), size))
+                } else {
+                    Some(Change::Update(path.to_string(), size))
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
 fn apply_exclude_from(rsync_command: &mut Command, exclude_file: Vec<String>) {
     exclude_file.into_iter().for_each(|glob| {
         rsync_command.arg(format!("--exclude={}", glob));
     });
 }
 
-struct Message;
+/// Filters that limit the pull to the paths and their parent directories.
+///
+/// Excluded files are not deleted locally, so files outside of the paths stay untouched.
+fn pull_path_filters(paths: &[String]) -> Vec<String> {
+    let paths: Vec<&str> = paths
+        .iter()
+        .map(|path| path.trim_start_matches("./").trim_matches('/'))
+        .collect();
+
+    // The project directory itself means everything.
+    if paths.is_empty() || paths.iter().any(|path| path.is_empty() || *path == ".") {
+        return Vec::new();
+    }
+
+    let mut filters: Vec<String> = Vec::new();
+
+    for path in paths {
+        let mut parent = String::new();
+
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            if !parent.is_empty() {
+                let filter = format!("--include=/{}/", parent);
+                if !filters.contains(&filter) {
+                    filters.push(filter);
+                }
+                parent.push('/');
+            }
+            parent.push_str(component);
+        }
+
+        filters.push(format!("--include=/{}", parent));
+        filters.push(format!("--include=/{}/***", parent));
+    }
+
+    filters.push(String::from("--exclude=*"));
+    filters
+}
+
+/// Logs rsync output line by line and keeps it for later parsing.
+#[derive(Default)]
+struct Message {
+    output: Vec<u8>,
+}
 
 impl Write for Message {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
@@ -341,6 +1232,7 @@ impl Write for Message {
             }
             tracing::debug!("{}", s);
         }
+        self.output.extend_from_slice(buf);
         Ok(buf.len())
     }
 
@@ -349,34 +1241,97 @@ impl Write for Message {
     }
 }
 
-fn execute_rsync(rsync: &mut Command) -> Result<(), String> {
-    let mut result = rsync
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .unwrap();
-
-    let mut message = Message;
-    io::copy(&mut result.stdout.take().unwrap(), &mut message)
-        .expect("Couldn't copy rsync result's stdout");
-    let mut err_message = Message;
-    io::copy(&mut result.stderr.take().unwrap(), &mut err_message)
-        .expect("Couldn't copy rsync result's stderr");
-
-    match result.wait_with_output() {
-        Err(_) => Err(String::from("Generic rsync error.")), // Rust doc doesn't really say when can an error occur.
-        Ok(output) => match output.status.code() {
-            None => Err(String::from("rsync was terminated.")),
+/// `rsync` exit codes of connection problems: socket I/O, protocol data stream,
+/// data send/receive and daemon connection timeouts, `ssh` connection errors.
+const TRANSIENT_RSYNC_EXIT_CODES: [i32; 5] = [10, 12, 30, 35, 255];
+
+struct RsyncErr {
+    message: String,
+    exit_code: Option<i32>,
+    timed_out: bool,
+}
+
+impl Transient for RsyncErr {
+    fn transient(&self) -> bool {
+        matches!(self.exit_code, Some(code) if TRANSIENT_RSYNC_EXIT_CODES.contains(&code))
+    }
+}
+
+/// Short form for retry logs, the message contains the whole `rsync` output.
+impl fmt::Display for RsyncErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.exit_code {
+            None => write!(f, "{}", self.message),
+            Some(exit_code) => write!(f, "rsync exit code {}", exit_code),
+        }
+    }
+}
+
+/// Returns the standard output of `rsync`.
+fn execute_rsync(rsync: &mut Command, timeout: Option<Duration>) -> Result<String, RsyncErr> {
+    let mut result =
+        process::spawn(rsync.stdout(Stdio::piped()).stderr(Stdio::piped()), timeout).unwrap();
+
+    let mut stdout = result.stdout.take().unwrap();
+    let mut stderr = result.stderr.take().unwrap();
+    let span = tracing::Span::current();
+
+    // Read both streams concurrently, otherwise rsync blocks once a pipe is full.
+    let message = thread::spawn({
+        let span = span.clone();
+        move || {
+            let _guard = span.enter();
+            let mut message = Message::default();
+            io::copy(&mut stdout, &mut message).expect("Couldn't copy rsync result's stdout");
+            message
+        }
+    });
+    let err_message = thread::spawn(move || {
+        let _guard = span.enter();
+        let mut err_message = Message::default();
+        io::copy(&mut stderr, &mut err_message).expect("Couldn't copy rsync result's stderr");
+        err_message
+    });
+
+    let status = process::wait(&mut result, timeout);
+
+    let message = message.join().expect("Couldn't read rsync result's stdout");
+    let err_message = err_message
+        .join()
+        .expect("Couldn't read rsync result's stderr");
+
+    let stdout = String::from_utf8_lossy(&message.output);
+    let stderr = String::from_utf8_lossy(&err_message.output);
+
+    let failed = |message: String, exit_code: Option<i32>| RsyncErr {
+        message,
+        exit_code,
+        timed_out: false,
+    };
+
+    match status {
+        Err(_) => Err(failed(String::from("Generic rsync error."), None)), // Rust doc doesn't really say when can an error occur.
+        Ok(None) => Err(RsyncErr {
+            message: format!(
+                "rsync timed out after {}",
+                format_duration(timeout.unwrap_or_default())
+            ),
+            exit_code: None,
+            timed_out: true,
+        }),
+        Ok(Some(status)) => match status.code() {
+            None => Err(failed(String::from("rsync was terminated."), None)),
             Some(status_code) => match status_code {
-                0 => Ok(()),
-                _ => Err(
+                0 => Ok(stdout.to_string()),
+                _ => Err(failed(
                     format!(
                         "rsync exit code '{exit_code}',\nrsync stdout '{stdout}',\nrsync stderr '{stderr}'.",
                         exit_code = status_code,
-                        stdout = String::from_utf8_lossy(&output.stdout),
-                        stderr = String::from_utf8_lossy(&output.stderr)
-                    )
-                )
+                        stdout = stdout,
+                        stderr = stderr
+                    ),
+                    Some(status_code)
+                ))
             }
         },
     }
@@ -395,6 +1350,198 @@ fn calculate_perceived_pull_duration(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn change_parse_itemized_lines() {
+        assert_eq!(
+            Change::parse(">f+++++++++ 1024 app/build/report.html"),
+            Some(Change::Create(String::from("app/build/report.html"), 1024))
+        );
+        assert_eq!(
+            Change::parse("<f.st...... 52 src/main file.rs"),
+            Some(Change::Update(String::from("src/main file.rs"), 52))
+        );
+        assert_eq!(
+            Change::parse("*deleting   0 build/old/"),
+            Some(Change::Delete(String::from("build/old/")))
+        );
+    }
+
+    #[test]
+    fn change_parse_skips_directories_and_other_lines() {
+        assert_eq!(Change::parse("cd+++++++++ 4096 build/"), None);
+        assert_eq!(Change::parse(".d..t...... 4096 ./"), None);
+        assert_eq!(Change::parse("sending incremental file list"), None);
+        assert_eq!(Change::parse("Number of files: 10"), None);
+    }
+
+    #[test]
+    fn pull_path_filters_everything() {
+        assert_eq!(pull_path_filters(&[]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn pull_path_filters_nested_paths() {
+        assert_eq!(
+            pull_path_filters(&[
+                String::from("./build/reports/"),
+                String::from("build/outputs"),
+                String::from("log.txt"),
+            ]),
+            vec![
+                "--include=/build/",
+                "--include=/build/reports",
+                "--include=/build/reports/***",
+                "--include=/build/outputs",
+                "--include=/build/outputs/***",
+                "--include=/log.txt",
+                "--include=/log.txt/***",
+                "--exclude=*",
+            ]
+        );
+    }
+
+    fn config() -> Config {
+        Config::from_file_contents("remote:\n  host: computer1\n").unwrap()
+    }
+
+    #[test]
+    fn is_local_state_paths() {
+        assert!(is_local_state(".mainframer/cache"));
+        assert!(is_local_state(".mainframer/cache/manifest"));
+        assert!(is_local_state(".mainframer/history.jsonl"));
+        assert!(is_local_state(".mainframer-push.json"));
+        assert!(!is_local_state(".mainframer/config.yml"));
+        assert!(!is_local_state(".mainframer/cached"));
+        assert!(!is_local_state("src/main.rs"));
+    }
+
+    #[test]
+    fn commands_exclude_local_state() {
+        let project = Path::new("/project");
+        let push = push_command(project, &config(), &None, None, false, 0);
+        let pull = pull_command(
+            project,
+            &config(),
+            &None,
+            &PullTarget::project(project),
+            false,
+            0,
+        );
+
+        for command in [push, pull] {
+            let args: Vec<_> = command.get_args().collect();
+
+            for excluded in [
+                "--exclude=/.mainframer/cache",
+                "--exclude=/.mainframer/history.jsonl",
+                "--exclude=/.mainframer/trash",
+            ] {
+                assert!(
+                    args.contains(&OsStr::new(excluded)),
+                    "{:?} doesn't have {}",
+                    command,
+                    excluded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn apply_tuning_arguments() {
+        let mut command = Command::new("rsync");
+        apply_tuning(
+            &mut command,
+            Some(500),
+            true,
+            true,
+            Some(String::from("zstd")),
+        );
+
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            vec![
+                "--bwlimit=500",
+                "--checksum",
+                "--partial",
+                "--compress-choice=zstd"
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_tuning_nothing() {
+        let mut command = Command::new("rsync");
+        apply_tuning(&mut command, None, false, false, None);
+
+        assert_eq!(command.get_args().count(), 0);
+    }
+
+    #[test]
+    fn rsync_stats_parse() {
+        let output = "
+Number of files: 1,234 (reg: 1,000, dir: 234)
+Number of created files: 0
+Number of deleted files: 0
+Number of regular files transferred: 5
+Total file size: 12,345,678 bytes
+Total transferred file size: 1,234 bytes
+Total bytes sent: 1,500
+Total bytes received: 35
+
+sent 1,500 bytes  received 35 bytes  3,070.00 bytes/sec
+total size is 12,345,678  speedup is 8,042.46
+";
+
+        assert_eq!(
+            RsyncStats::parse(output),
+            RsyncStats {
+                files: 1234,
+                files_transferred: 5,
+                total_size: 12345678,
+                bytes_sent: 1500,
+                bytes_received: 35,
+            }
+        );
+    }
+
+    #[test]
+    fn rsync_stats_parse_rsync_2() {
+        let output = "
+Number of files: 1234
+Number of files transferred: 5
+Total bytes sent: 1500
+Total bytes received: 35
+";
+
+        assert_eq!(
+            RsyncStats::parse(output),
+            RsyncStats {
+                files: 1234,
+                files_transferred: 5,
+                total_size: 0,
+                bytes_sent: 1500,
+                bytes_received: 35,
+            }
+        );
+    }
+
+    #[test]
+    fn rsync_stats_speedup() {
+        let stats = RsyncStats {
+            total_size: 3000,
+            bytes_sent: 1000,
+            bytes_received: 500,
+            ..Default::default()
+        };
+        assert_eq!(stats.speedup(), 2.0);
+    }
+
+    #[test]
+    fn rsync_stats_speedup_nothing_sent() {
+        assert_eq!(RsyncStats::default().speedup(), 0.0);
+    }
 
     #[test]
     fn calculate_perceived_pull_duration_equals() {
@@ -419,4 +1566,18 @@ mod tests {
             Duration::from_secs(0)
         );
     }
+
+    #[test]
+    fn rsync_err_transient() {
+        let err = |exit_code: Option<i32>| RsyncErr {
+            message: String::new(),
+            exit_code,
+            timed_out: false,
+        };
+
+        assert!(err(Some(255)).transient());
+        assert!(err(Some(12)).transient());
+        assert!(!err(Some(23)).transient());
+        assert!(!err(None).transient());
+    }
 }
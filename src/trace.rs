@@ -0,0 +1,319 @@
+use std::cell::Cell;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Target for spans that only matter for the trace and are hidden from the log.
+pub const TARGET: &str = "mainframer::trace";
+
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+static NEXT_THREAD: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static THREAD: Cell<u64> = const { Cell::new(0) };
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, ValueEnum)]
+pub enum TraceFormat {
+    /// Chrome `trace_event` JSON, opens in chrome://tracing and Perfetto.
+    Chrome,
+    /// OpenTelemetry OTLP JSON.
+    Otlp,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct SpanRecord {
+    parent: Option<usize>,
+    name: &'static str,
+    fields: Vec<(String, String)>,
+    thread: u64,
+    /// Since the start of the recording.
+    start: Duration,
+    /// `None` while the span is open.
+    end: Option<Duration>,
+}
+
+struct Recorder {
+    path: PathBuf,
+    format: TraceFormat,
+    start: Instant,
+    start_time: SystemTime,
+    spans: Vec<SpanRecord>,
+}
+
+/// Index of the span in the recorder, span ids get reused by `tracing`.
+struct SpanIndex(usize);
+
+/// Records spans so they can be written with [`finish`], does nothing until [`init`] is called.
+pub struct TraceLayer;
+
+/// Starts recording spans, the trace gets written to the path once [`finish`] is called.
+pub fn init(path: PathBuf, format: TraceFormat) {
+    *RECORDER.lock().unwrap() = Some(Recorder {
+        path,
+        format,
+        start: Instant::now(),
+        start_time: SystemTime::now(),
+        spans: Vec::new(),
+    });
+}
+
+/// Writes the recorded trace if recording was started, spans that are still open end now.
+pub fn finish() {
+    let recorder = match RECORDER.lock().unwrap().take() {
+        None => return,
+        Some(recorder) => recorder,
+    };
+
+    let now = recorder.start.elapsed();
+    let spans: Vec<SpanRecord> = recorder
+        .spans
+        .into_iter()
+        .map(|span| SpanRecord {
+            end: span.end.or(Some(now)),
+            ..span
+        })
+        .collect();
+
+    let trace = match recorder.format {
+        TraceFormat::Chrome => to_chrome(&spans),
+        TraceFormat::Otlp => to_otlp(&spans, recorder.start_time),
+    };
+
+    match fs::write(&recorder.path, trace.to_string()) {
+        Err(error) => tracing::warn!("Could not write trace to {:?}: {}", recorder.path, error),
+        Ok(_) => tracing::info!("Trace written to {}.", recorder.path.to_string_lossy()),
+    }
+}
+
+fn current_thread() -> u64 {
+    THREAD.with(|thread| {
+        if thread.get() == 0 {
+            thread.set(NEXT_THREAD.fetch_add(1, Ordering::SeqCst));
+        }
+        thread.get()
+    })
+}
+
+struct FieldVisitor<'a>(&'a mut Vec<(String, String)>);
+
+impl<'a> Visit for FieldVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name().to_string(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .push((field.name().to_string(), format!("{:?}", value)));
+    }
+}
+
+impl<S> Layer<S> for TraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            None => return,
+            Some(span) => span,
+        };
+
+        let parent = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<SpanIndex>().map(|index| index.0));
+
+        let mut fields: Vec<(String, String)> = Vec::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+
+        let mut recorder = RECORDER.lock().unwrap();
+
+        if let Some(recorder) = recorder.as_mut() {
+            let index = recorder.spans.len();
+
+            recorder.spans.push(SpanRecord {
+                parent,
+                name: attrs.metadata().name(),
+                fields,
+                thread: current_thread(),
+                start: recorder.start.elapsed(),
+                end: None,
+            });
+
+            span.extensions_mut().insert(SpanIndex(index));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let index = match ctx
+            .span(&id)
+            .and_then(|span| span.extensions().get::<SpanIndex>().map(|index| index.0))
+        {
+            None => return,
+            Some(index) => index,
+        };
+
+        if let Some(recorder) = RECORDER.lock().unwrap().as_mut() {
+            let end = recorder.start.elapsed();
+            if let Some(span) = recorder.spans.get_mut(index) {
+                span.end = Some(end);
+            }
+        }
+    }
+}
+
+fn to_chrome(spans: &[SpanRecord]) -> Value {
+    let pid = std::process::id();
+
+    let events: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let end = span.end.unwrap_or(span.start);
+            let args: serde_json::Map<String, Value> = span
+                .fields
+                .iter()
+                .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+                .collect();
+
+            json!({
+                "name": span.name,
+                "cat": "mainframer",
+                "ph": "X",
+                "ts": span.start.as_micros() as u64,
+                "dur": end.saturating_sub(span.start).as_micros() as u64,
+                "pid": pid,
+                "tid": span.thread,
+                "args": args,
+            })
+        })
+        .collect();
+
+    json!({
+        "traceEvents": events,
+        "displayTimeUnit": "ms",
+    })
+}
+
+fn to_otlp(spans: &[SpanRecord], start_time: SystemTime) -> Value {
+    let start_nanos = start_time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+
+    // Unique enough for a single run, OTLP requires 16 non-zero bytes.
+    let trace_id = format!(
+        "{:016x}{:016x}",
+        start_nanos,
+        u64::from(std::process::id()).max(1)
+    );
+    let span_id = |index: usize| format!("{:016x}", index + 1);
+
+    let otlp_spans: Vec<Value> = spans
+        .iter()
+        .enumerate()
+        .map(|(index, span)| {
+            let end = span.end.unwrap_or(span.start);
+            let attributes: Vec<Value> = span
+                .fields
+                .iter()
+                .map(|(name, value)| json!({ "key": name, "value": { "stringValue": value } }))
+                .collect();
+
+            json!({
+                "traceId": trace_id,
+                "spanId": span_id(index),
+                "parentSpanId": span.parent.map(span_id).unwrap_or_default(),
+                "name": span.name,
+                "kind": 1,
+                "startTimeUnixNano": (start_nanos + span.start.as_nanos() as u64).to_string(),
+                "endTimeUnixNano": (start_nanos + end.as_nanos() as u64).to_string(),
+                "attributes": attributes,
+            })
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": "mainframer" } }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "mainframer", "version": env!("CARGO_PKG_VERSION") },
+                "spans": otlp_spans,
+            }],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans() -> Vec<SpanRecord> {
+        vec![
+            SpanRecord {
+                parent: None,
+                name: "push",
+                fields: vec![(String::from("host"), String::from("computer1"))],
+                thread: 1,
+                start: Duration::from_millis(1),
+                end: Some(Duration::from_millis(3)),
+            },
+            SpanRecord {
+                parent: Some(0),
+                name: "rsync",
+                fields: Vec::new(),
+                thread: 2,
+                start: Duration::from_millis(2),
+                end: Some(Duration::from_millis(3)),
+            },
+        ]
+    }
+
+    #[test]
+    fn to_chrome_complete_events() {
+        let trace = to_chrome(&spans());
+        let events = trace["traceEvents"].as_array().unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["name"], "push");
+        assert_eq!(events[0]["ph"], "X");
+        assert_eq!(events[0]["ts"], 1000);
+        assert_eq!(events[0]["dur"], 2000);
+        assert_eq!(events[0]["tid"], 1);
+        assert_eq!(events[0]["args"]["host"], "computer1");
+    }
+
+    #[test]
+    fn to_otlp_spans_with_parents() {
+        let trace = to_otlp(&spans(), UNIX_EPOCH + Duration::from_secs(1));
+        let otlp_spans = trace["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+
+        assert_eq!(otlp_spans.len(), 2);
+        assert_eq!(otlp_spans[0]["spanId"], "0000000000000001");
+        assert_eq!(otlp_spans[0]["parentSpanId"], "");
+        assert_eq!(otlp_spans[1]["parentSpanId"], "0000000000000001");
+        assert_eq!(otlp_spans[0]["startTimeUnixNano"], "1001000000");
+        assert_eq!(otlp_spans[0]["endTimeUnixNano"], "1003000000");
+        assert_eq!(otlp_spans[0]["attributes"][0]["key"], "host");
+        assert_eq!(
+            otlp_spans[0]["traceId"].as_str().unwrap().len(),
+            32,
+            "Trace id must be 16 bytes"
+        );
+    }
+}
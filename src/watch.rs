@@ -0,0 +1,402 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, select, unbounded, Sender};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::ignore::Ignore;
+use crate::pool;
+use crate::process::Deadline;
+use crate::ssh;
+use crate::sync;
+use crate::time::format_duration;
+
+/// Quiet period after the last change before pushing, editors save in bursts.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// More changed files than this get pushed by comparing the whole project.
+const MAX_LISTED_FILES: usize = 1000;
+
+/// How long a sync request gives the file watcher to deliver events of changes made before it.
+const SETTLE: Duration = Duration::from_millis(50);
+
+/// How long a run waits for the pending changes to get pushed without `push.timeout` and `--timeout`.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "lowercase")]
+enum Request {
+    /// Push pending changes right away and report the result.
+    Sync,
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    in_sync: bool,
+    host: String,
+}
+
+pub fn socket_path(local_dir_absolute_path: &Path) -> PathBuf {
+    local_dir_absolute_path
+        .join(".mainframer")
+        .join("cache")
+        .join("watch.sock")
+}
+
+/// Host the running `mainframer watch` has pushed all local changes to, `None` without one.
+///
+/// Waits for the pending push as long as the push of the run could take.
+pub fn synced_host(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    deadline: Deadline,
+) -> Option<String> {
+    let timeout = deadline
+        .timeout(config.push.timeout.map(Duration::from_secs))
+        .unwrap_or(SYNC_TIMEOUT);
+
+    let mut stream = UnixStream::connect(socket_path(local_dir_absolute_path)).ok()?;
+    // Zero is an error, the run is out of time then anyway.
+    stream.set_read_timeout(Some(timeout)).ok()?;
+
+    let request = serde_json::to_string(&Request::Sync).ok()?;
+    writeln!(stream, "{}", request).ok()?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).ok()?;
+
+    let response: Response = serde_json::from_str(&line).ok()?;
+
+    if response.in_sync {
+        Some(response.host)
+    } else {
+        None
+    }
+}
+
+/// Pushes local changes as they happen until killed.
+pub fn run(
+    local_dir_absolute_path: &Path,
+    config: &Config,
+    ignore: &Option<Ignore>,
+    verbose: u8,
+) -> Result<(), String> {
+    let mut config = config.clone();
+    config.remote.host = pool::select_host(&config, local_dir_absolute_path)?;
+
+    let listener = bind(&socket_path(local_dir_absolute_path))?;
+
+    let (event_tx, event_rx) = unbounded();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = event_tx.send(event);
+    })
+    .map_err(|error| format!("Could not watch the project: {}", error))?;
+
+    watcher
+        .watch(local_dir_absolute_path, RecursiveMode::Recursive)
+        .map_err(|error| format!("Could not watch the project: {}", error))?;
+
+    let (request_tx, request_rx) = unbounded();
+    thread::spawn(move || serve(listener, request_tx));
+
+    // Kept open for the lifetime of the daemon, every push reuses them.
+    let _connections = ssh::Connections::open(&config);
+
+    let mut watch = Watch {
+        local_dir_absolute_path,
+        config: &config,
+        ignore,
+        verbose,
+        pending: BTreeSet::new(),
+        full: true,
+        synced: false,
+    };
+
+    tracing::info!(
+        "Watching {:?}, pushing changes to {}.",
+        local_dir_absolute_path,
+        config.remote.host
+    );
+
+    watch.push();
+
+    loop {
+        select! {
+            recv(event_rx) -> event => match event {
+                Err(_) => return Err(String::from("File watcher has stopped.")),
+                Ok(event) => watch.handle(event),
+            },
+            recv(request_rx) -> reply => {
+                let reply: Sender<Response> = match reply {
+                    Err(_) => return Err(String::from("Socket listener has stopped.")),
+                    Ok(reply) => reply,
+                };
+
+                // Changes saved right before the run have to be pushed before it, not after.
+                thread::sleep(SETTLE);
+
+                for event in event_rx.try_iter() {
+                    watch.handle(event);
+                }
+
+                watch.push();
+
+                let _ = reply.send(Response {
+                    in_sync: watch.synced,
+                    host: config.remote.host.clone(),
+                });
+            },
+            default(DEBOUNCE) => watch.push(),
+        }
+    }
+}
+
+struct Watch<'a> {
+    local_dir_absolute_path: &'a Path,
+    config: &'a Config,
+    ignore: &'a Option<Ignore>,
+    verbose: u8,
+    /// Changed files relative to the project.
+    pending: BTreeSet<String>,
+    /// Whether the next push has to compare the whole project.
+    full: bool,
+    /// Whether the remote machine has all the changes pushed so far.
+    synced: bool,
+}
+
+impl Watch<'_> {
+    fn handle(&mut self, event: notify::Result<Event>) {
+        match event {
+            Err(error) => {
+                // Missed events, like after an inotify queue overflow.
+                tracing::warn!("File watcher error: {}", error);
+                self.full = true;
+            }
+            Ok(event) => self.collect(&event),
+        }
+    }
+
+    fn collect(&mut self, event: &Event) {
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+
+        for path in &event.paths {
+            let relative = match path.strip_prefix(self.local_dir_absolute_path) {
+                Ok(relative) => relative.to_string_lossy().to_string(),
+                Err(_) => continue,
+            };
+
+            // Runs and pushes write their own state, like the socket and the manifest cache.
+            if relative.is_empty() || sync::is_local_state(&relative) {
+                continue;
+            }
+
+            // A listed directory would be pushed without its contents.
+            let is_dir = path.is_dir();
+
+            if matches!(self.ignore, Some(ignore) if ignore.excludes_push(&relative, is_dir)) {
+                continue;
+            }
+
+            if is_dir {
+                self.full = true;
+            } else {
+                self.pending.insert(relative);
+            }
+        }
+    }
+
+    fn push(&mut self) {
+        if !self.full && self.pending.is_empty() {
+            return;
+        }
+
+        let files: Vec<String> = std::mem::take(&mut self.pending).into_iter().collect();
+        let full = self.full || files.len() > MAX_LISTED_FILES;
+        let deadline = Deadline::after(None, Instant::now());
+
+        let result = if full {
+            sync::push(
+                self.local_dir_absolute_path,
+                self.config,
+                self.ignore,
+                deadline,
+                self.verbose,
+            )
+        } else {
+            sync::push_files(
+                self.local_dir_absolute_path,
+                self.config,
+                self.ignore,
+                &files,
+                deadline,
+                self.verbose,
+            )
+        };
+
+        match result {
+            Err(err) => {
+                tracing::error!(
+                    "Push failed: {}, took {}.",
+                    err.message,
+                    format_duration(err.duration)
+                );
+                // Files of the failed push are unknown to the remote machine.
+                self.full = true;
+                self.synced = false;
+            }
+            Ok(ok) => {
+                if full {
+                    tracing::info!("Pushed the project, took {}.", format_duration(ok.duration));
+                } else {
+                    tracing::info!(
+                        "Pushed {} changed files, took {}.",
+                        files.len(),
+                        format_duration(ok.duration)
+                    );
+                }
+                self.full = false;
+                self.synced = true;
+            }
+        }
+    }
+}
+
+/// Binds the socket unless another daemon is listening on it already.
+fn bind(socket: &Path) -> Result<UnixListener, String> {
+    if UnixStream::connect(socket).is_ok() {
+        return Err(String::from(
+            "Another mainframer watch is already running for the project.",
+        ));
+    }
+
+    // Left by a daemon that has been killed.
+    let _ = fs::remove_file(socket);
+
+    if let Some(dir) = socket.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|error| format!("Could not create {:?}: {}", dir, error))?;
+    }
+
+    UnixListener::bind(socket)
+        .map_err(|error| format!("Could not listen on {:?}: {}", socket, error))
+}
+
+fn serve(listener: UnixListener, request_tx: Sender<Sender<Response>>) {
+    for stream in listener.incoming().flatten() {
+        if let Err(error) = answer(stream, &request_tx) {
+            tracing::debug!("Could not answer a run: {}", error);
+        }
+    }
+}
+
+fn answer(stream: UnixStream, request_tx: &Sender<Sender<Response>>) -> Result<(), String> {
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|error| error.to_string())?;
+
+    match serde_json::from_str(&line).map_err(|error| error.to_string())? {
+        Request::Sync => {
+            let (reply_tx, reply_rx) = bounded(1);
+            request_tx
+                .send(reply_tx)
+                .map_err(|error| error.to_string())?;
+
+            let response = reply_rx.recv().map_err(|error| error.to_string())?;
+            let json = serde_json::to_string(&response).map_err(|error| error.to_string())?;
+
+            writeln!(&stream, "{}", json).map_err(|error| error.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watch<'a>(local_dir_absolute_path: &'a Path, config: &'a Config) -> Watch<'a> {
+        Watch {
+            local_dir_absolute_path,
+            config,
+            ignore: &None,
+            verbose: 0,
+            pending: BTreeSet::new(),
+            full: false,
+            synced: true,
+        }
+    }
+
+    #[test]
+    fn handle_collects_changed_files() {
+        let config = Config::from_file_contents("remote:\n  host: computer1\n").unwrap();
+        let mut watch = watch(Path::new("/project"), &config);
+
+        watch.handle(Ok(
+            Event::new(EventKind::Any).add_path(PathBuf::from("/project/src/main.rs"))
+        ));
+
+        assert_eq!(
+            watch.pending.into_iter().collect::<Vec<_>>(),
+            vec![String::from("src/main.rs")]
+        );
+        assert!(!watch.full);
+    }
+
+    #[test]
+    fn handle_skips_local_state() {
+        let config = Config::from_file_contents("remote:\n  host: computer1\n").unwrap();
+        let mut watch = watch(Path::new("/project"), &config);
+
+        for path in [
+            "/project/.mainframer/cache/manifest",
+            "/project/.mainframer/history.jsonl",
+            "/project/.mainframer-push.json",
+        ] {
+            watch.handle(Ok(Event::new(EventKind::Any).add_path(PathBuf::from(path))));
+        }
+
+        assert!(watch.pending.is_empty());
+        assert!(!watch.full);
+    }
+
+    #[test]
+    fn handle_error_forces_full_push() {
+        let config = Config::from_file_contents("remote:\n  host: computer1\n").unwrap();
+        let mut watch = watch(Path::new("/project"), &config);
+
+        watch.handle(Err(notify::Error::generic("queue overflow")));
+
+        assert!(watch.full);
+    }
+
+    #[test]
+    fn request_format() {
+        assert_eq!(
+            serde_json::to_string(&Request::Sync).unwrap(),
+            r#"{"request":"sync"}"#
+        );
+    }
+
+    #[test]
+    fn response_format() {
+        let response: Response =
+            serde_json::from_str(r#"{"inSync":true,"host":"build-1"}"#).unwrap();
+        assert_eq!(
+            response,
+            Response {
+                in_sync: true,
+                host: String::from("build-1"),
+            }
+        );
+    }
+}